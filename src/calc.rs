@@ -1,3 +1,5 @@
+use crate::url_props::{CropBox, FitIn, UrlProps};
+
 /**
  * Returns the aspect ratio of a rectangle.
  * If is less than 1 is portrait, if is greater than 1 is landscape.
@@ -6,14 +8,86 @@ pub fn get_aspect_ratio(width: i32, height: i32) -> f32 {
     width as f32 / height as f32
 }
 
+/**
+ * Returns `side * numerator / denominator`, multiplied in i64 so url sizes like `0x999999999`
+ * do not overflow, and clamped to the i32 range.
+ */
+fn scale_side(side: i32, numerator: i32, denominator: i32) -> i32 {
+    (side as i64 * numerator as i64 / denominator as i64).clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
 pub fn new_width_when_respect_aspect_ration(original_width: i32, original_height: i32, new_height: i32) -> i32 {
-    // ((original_width as f32 / original_height as f32) * new_height as f32) as i32
-    original_width * new_height / original_height
+    scale_side(original_width, new_height, original_height)
 }
 
 pub fn new_height_when_respect_aspect_ration(original_width: i32, original_height: i32, new_width: i32) -> i32 {
-    // ((original_height as f32 / original_width as f32) * new_width as f32) as i32
-    original_height * new_width / original_width
+    scale_side(original_height, new_width, original_width)
+}
+
+/**
+ * Returns the width and height requested in the url, where a zero side
+ * is calculated from the other one respecting the aspect ratio.
+ */
+pub fn get_target_size(original_size: opencv::core::Size, width: i32, height: i32) -> opencv::core::Size {
+    let (mut new_width, mut new_height) = (width, height);
+
+    if new_width == 0 {
+        new_width = new_width_when_respect_aspect_ration(original_size.width, original_size.height, new_height);
+    }
+
+    if new_height == 0 {
+        new_height = new_height_when_respect_aspect_ration(original_size.width, original_size.height, new_width);
+    }
+
+    opencv::core::Size { width: new_width, height: new_height }
+}
+
+/**
+ * Returns the final width and height for the url.
+ *
+ * `orig` keeps the original side, and a url without size (or `0x0`) keeps the original size.
+ * When upscale is not allowed, the size is reduced keeping its aspect ratio, so the image
+ * is never resized above its original size.
+ */
+pub fn get_url_target_size(original_size: opencv::core::Size, url_props: &UrlProps, allow_upscale: bool) -> opencv::core::Size {
+    let (width, height) = match url_props.size {
+        Some(size) => (size.width.resolve(original_size.width), size.height.resolve(original_size.height)),
+        None => (url_props.width, url_props.height),
+    };
+
+    let target_size = if width == 0 && height == 0 {
+        original_size
+    } else {
+        get_target_size(original_size, width, height)
+    };
+
+    if allow_upscale {
+        return target_size;
+    }
+
+    let resize_size = get_resize_size(original_size, target_size, url_props.fit_in);
+    if resize_size.width <= original_size.width && resize_size.height <= original_size.height {
+        return target_size;
+    }
+
+    let factor = f32::min(
+        original_size.width as f32 / resize_size.width as f32,
+        original_size.height as f32 / resize_size.height as f32,
+    );
+    opencv::core::Size {
+        width: (target_size.width as f32 * factor).round() as i32,
+        height: (target_size.height as f32 * factor).round() as i32,
+    }
+}
+
+/**
+ * Returns the size the image is resized to, before the crop by alignment.
+ */
+pub fn get_resize_size(original_size: opencv::core::Size, target_size: opencv::core::Size, fit_in: Option<FitIn>) -> opencv::core::Size {
+    match fit_in {
+        Some(fit_in) => get_fit_in_size(original_size, target_size, fit_in),
+        None => get_new_size_respecting_aspect_ratio(original_size, target_size),
+    }
 }
 
 /**
@@ -38,10 +112,92 @@ pub fn get_new_size_respecting_aspect_ratio(original_size: opencv::core::Size, n
     opencv::core::Size { width: new_width, height: new_height }
 }
 
+/**
+ * Returns the size of the image for the fit-in modes, where nothing is cropped.
+ *
+ * fit-in: the whole image fits inside the box. Images smaller than the box are kept as they are.
+ * adaptive-fit-in: same as fit-in, but the box is rotated when its orientation differs from the image.
+ * full-fit-in: the smaller side of the image fits the box, so the image covers the whole box.
+ */
+pub fn get_fit_in_size(original_size: opencv::core::Size, new_size: opencv::core::Size, fit_in: FitIn) -> opencv::core::Size {
+    let (source_width, source_height) = (original_size.width as f32, original_size.height as f32);
+    let mut target_width = (if new_size.width > 0 { new_size.width } else { original_size.width }) as f32;
+    let mut target_height = (if new_size.height > 0 { new_size.height } else { original_size.height }) as f32;
+
+    let adaptive = matches!(fit_in, FitIn::AdaptiveFitIn | FitIn::AdaptiveFullFitIn);
+    let full = matches!(fit_in, FitIn::FullFitIn | FitIn::AdaptiveFullFitIn);
+
+    let is_source_landscape = source_width > source_height;
+    let is_target_landscape = target_width > target_height;
+    if adaptive && source_width != source_height && target_width != target_height && is_source_landscape != is_target_landscape {
+        std::mem::swap(&mut target_width, &mut target_height);
+    }
+
+    if !full && target_width >= source_width && target_height >= source_height {
+        return original_size;
+    }
+
+    let fit_width = if full {
+        source_width / target_width <= source_height / target_height
+    } else {
+        source_width / target_width >= source_height / target_height
+    };
+
+    if fit_width {
+        opencv::core::Size {
+            width: target_width as i32,
+            height: (source_height * target_width / source_width).round() as i32,
+        }
+    } else {
+        opencv::core::Size {
+            width: (source_width * target_height / source_height).round() as i32,
+            height: target_height as i32,
+        }
+    }
+}
+
+/**
+ * Returns the manual crop box as a rectangle clamped to the image bounds.
+ * Returns `None` when nothing of the box is left inside the image.
+ */
+pub fn get_crop_rect(crop_box: &CropBox, image_size: opencv::core::Size) -> Option<opencv::core::Rect> {
+    let left = crop_box.left.clamp(0, image_size.width);
+    let top = crop_box.top.clamp(0, image_size.height);
+    let right = crop_box.right.clamp(0, image_size.width);
+    let bottom = crop_box.bottom.clamp(0, image_size.height);
+
+    if right <= left || bottom <= top {
+        return None;
+    }
+
+    Some(opencv::core::Rect { x: left, y: top, width: right - left, height: bottom - top })
+}
+
+/**
+ * Returns the rectangle of the resized image that is kept, as centred on the focal point as the
+ * image allows. The focal point is in coordinates of the original image.
+ */
+pub fn get_focal_crop_rect(
+    original_size: opencv::core::Size,
+    resized_size: opencv::core::Size,
+    target_size: opencv::core::Size,
+    focal_point: opencv::core::Point2f,
+) -> opencv::core::Rect {
+    let scale_x = resized_size.width as f32 / original_size.width as f32;
+    let scale_y = resized_size.height as f32 / original_size.height as f32;
+    let center_x = (focal_point.x * scale_x).round() as i32;
+    let center_y = (focal_point.y * scale_y).round() as i32;
+
+    let x = (center_x - target_size.width / 2).clamp(0, (resized_size.width - target_size.width).max(0));
+    let y = (center_y - target_size.height / 2).clamp(0, (resized_size.height - target_size.height).max(0));
+    opencv::core::Rect { x, y, width: target_size.width, height: target_size.height }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+    use crate::url_parser;
 
     #[test]
     fn test_get_aspect_ratio() {
@@ -51,6 +207,61 @@ mod tests {
         assert_eq!(get_aspect_ratio(3456, 5184), 0.666_666_7);
     }
 
+    #[test]
+    fn test_get_target_size() {
+        let original_size = opencv::core::Size { width: 3456, height: 5184 };
+        assert_eq!(get_target_size(original_size, 670, 390), opencv::core::Size { width: 670, height: 390 });
+        assert_eq!(get_target_size(original_size, 670, 0), opencv::core::Size { width: 670, height: 1005 });
+        assert_eq!(get_target_size(original_size, 0, 390), opencv::core::Size { width: 260, height: 390 });
+    }
+
+    #[test]
+    fn test_get_target_size_does_not_overflow() {
+        assert_eq!(new_width_when_respect_aspect_ration(3000, 1000, 999999999), i32::MAX);
+        assert_eq!(new_height_when_respect_aspect_ration(1000, 3000, 999999999), i32::MAX);
+        assert_eq!(
+            get_target_size(opencv::core::Size { width: 3000, height: 1000 }, 0, 999999999),
+            opencv::core::Size { width: i32::MAX, height: 999999999 }
+        );
+        assert_eq!(
+            get_target_size(opencv::core::Size { width: 5184, height: 3456 }, 0, 1000000),
+            opencv::core::Size { width: 1500000, height: 1000000 }
+        );
+    }
+
+    #[test]
+    fn test_get_url_target_size_with_original_size() {
+        let original_size = opencv::core::Size { width: 3456, height: 5184 };
+        for path in ["0x0/big.jpg", "x/big.jpg", "origxorig/big.jpg", "big.jpg"] {
+            let url_props = url_parser::parse(path).unwrap();
+            assert_eq!(get_url_target_size(original_size, &url_props, true), original_size);
+        }
+
+        let url_props = url_parser::parse("origx1000/big.jpg").unwrap();
+        assert_eq!(get_url_target_size(original_size, &url_props, true), opencv::core::Size { width: 3456, height: 1000 });
+    }
+
+    #[test]
+    fn test_get_url_target_size_with_upscale() {
+        let original_size = opencv::core::Size { width: 300, height: 200 };
+        let url_props = url_parser::parse("600x600/small.jpg").unwrap();
+        assert_eq!(get_url_target_size(original_size, &url_props, true), opencv::core::Size { width: 600, height: 600 });
+    }
+
+    #[test]
+    fn test_get_url_target_size_without_upscale() {
+        let original_size = opencv::core::Size { width: 300, height: 200 };
+
+        let url_props = url_parser::parse("600x600/small.jpg").unwrap();
+        assert_eq!(get_url_target_size(original_size, &url_props, false), opencv::core::Size { width: 200, height: 200 });
+
+        let url_props = url_parser::parse("150x0/small.jpg").unwrap();
+        assert_eq!(get_url_target_size(original_size, &url_props, false), opencv::core::Size { width: 150, height: 100 });
+
+        let url_props = url_parser::parse("full-fit-in/1200x400/small.jpg").unwrap();
+        assert_eq!(get_url_target_size(original_size, &url_props, false), opencv::core::Size { width: 300, height: 100 });
+    }
+
     #[test]
     fn test_get_new_size_respecting_aspect_ratio_in_portrait() {
         let original_size = opencv::core::Size { width: 3456, height: 5184 };
@@ -68,4 +279,129 @@ mod tests {
         assert_eq!(new_size_with_aspect.width, 1005);
         assert_eq!(new_size_with_aspect.height, 670);
     }
+
+    #[test]
+    fn test_get_fit_in_size_in_portrait() {
+        let original_size = opencv::core::Size { width: 3456, height: 5184 };
+        let new_size = opencv::core::Size { width: 670, height: 390 };
+        let fit_in_size = get_fit_in_size(original_size, new_size, FitIn::FitIn);
+        assert_eq!(fit_in_size.width, 260);
+        assert_eq!(fit_in_size.height, 390);
+    }
+
+    #[test]
+    fn test_get_fit_in_size_in_landscape() {
+        let original_size = opencv::core::Size { width: 5184, height: 3456 };
+        let new_size = opencv::core::Size { width: 390, height: 670 };
+        let fit_in_size = get_fit_in_size(original_size, new_size, FitIn::FitIn);
+        assert_eq!(fit_in_size.width, 390);
+        assert_eq!(fit_in_size.height, 260);
+    }
+
+    #[test]
+    fn test_get_fit_in_size_when_image_is_smaller() {
+        let original_size = opencv::core::Size { width: 300, height: 200 };
+        let new_size = opencv::core::Size { width: 670, height: 390 };
+        let fit_in_size = get_fit_in_size(original_size, new_size, FitIn::FitIn);
+        assert_eq!(fit_in_size, original_size);
+    }
+
+    #[test]
+    fn test_get_fit_in_size_with_adaptive_in_portrait() {
+        let original_size = opencv::core::Size { width: 3456, height: 5184 };
+        let new_size = opencv::core::Size { width: 670, height: 390 };
+        let fit_in_size = get_fit_in_size(original_size, new_size, FitIn::AdaptiveFitIn);
+        assert_eq!(fit_in_size.width, 390);
+        assert_eq!(fit_in_size.height, 585);
+    }
+
+    #[test]
+    fn test_get_fit_in_size_with_adaptive_in_same_orientation() {
+        let original_size = opencv::core::Size { width: 5184, height: 3456 };
+        let new_size = opencv::core::Size { width: 670, height: 390 };
+        let fit_in_size = get_fit_in_size(original_size, new_size, FitIn::AdaptiveFitIn);
+        assert_eq!(fit_in_size.width, 585);
+        assert_eq!(fit_in_size.height, 390);
+    }
+
+    #[test]
+    fn test_get_fit_in_size_with_full_in_portrait() {
+        let original_size = opencv::core::Size { width: 3456, height: 5184 };
+        let new_size = opencv::core::Size { width: 670, height: 390 };
+        let fit_in_size = get_fit_in_size(original_size, new_size, FitIn::FullFitIn);
+        assert_eq!(fit_in_size.width, 670);
+        assert_eq!(fit_in_size.height, 1005);
+    }
+
+    #[test]
+    fn test_get_fit_in_size_with_full_in_landscape() {
+        let original_size = opencv::core::Size { width: 5184, height: 3456 };
+        let new_size = opencv::core::Size { width: 390, height: 670 };
+        let fit_in_size = get_fit_in_size(original_size, new_size, FitIn::FullFitIn);
+        assert_eq!(fit_in_size.width, 1005);
+        assert_eq!(fit_in_size.height, 670);
+    }
+
+    #[test]
+    fn test_get_fit_in_size_with_adaptive_full_in_portrait() {
+        let original_size = opencv::core::Size { width: 3456, height: 5184 };
+        let new_size = opencv::core::Size { width: 670, height: 390 };
+        let fit_in_size = get_fit_in_size(original_size, new_size, FitIn::AdaptiveFullFitIn);
+        assert_eq!(fit_in_size.width, 447);
+        assert_eq!(fit_in_size.height, 670);
+    }
+
+    #[test]
+    fn test_get_crop_rect_inside_image() {
+        let crop_box = CropBox { left: 10, top: 20, right: 300, bottom: 400 };
+        let rect = get_crop_rect(&crop_box, opencv::core::Size { width: 500, height: 500 }).unwrap();
+        assert_eq!(rect, opencv::core::Rect { x: 10, y: 20, width: 290, height: 380 });
+    }
+
+    #[test]
+    fn test_get_crop_rect_clamped_to_image() {
+        let crop_box = CropBox { left: 10, top: 20, right: 800, bottom: 900 };
+        let rect = get_crop_rect(&crop_box, opencv::core::Size { width: 500, height: 400 }).unwrap();
+        assert_eq!(rect, opencv::core::Rect { x: 10, y: 20, width: 490, height: 380 });
+    }
+
+    #[test]
+    fn test_get_crop_rect_outside_image() {
+        let crop_box = CropBox { left: 600, top: 20, right: 800, bottom: 300 };
+        assert_eq!(get_crop_rect(&crop_box, opencv::core::Size { width: 500, height: 400 }), None);
+
+        let inverted_box = CropBox { left: 300, top: 20, right: 100, bottom: 300 };
+        assert_eq!(get_crop_rect(&inverted_box, opencv::core::Size { width: 500, height: 400 }), None);
+    }
+
+    #[test]
+    fn test_get_focal_crop_rect_centred() {
+        let original_size = opencv::core::Size { width: 2000, height: 1000 };
+        let resized_size = opencv::core::Size { width: 400, height: 200 };
+        let target_size = opencv::core::Size { width: 200, height: 200 };
+        let focal_point = opencv::core::Point2f { x: 1000.0, y: 500.0 };
+        assert_eq!(
+            get_focal_crop_rect(original_size, resized_size, target_size, focal_point),
+            opencv::core::Rect { x: 100, y: 0, width: 200, height: 200 }
+        );
+    }
+
+    #[test]
+    fn test_get_focal_crop_rect_clamped_to_image() {
+        let original_size = opencv::core::Size { width: 2000, height: 1000 };
+        let resized_size = opencv::core::Size { width: 400, height: 200 };
+        let target_size = opencv::core::Size { width: 200, height: 200 };
+
+        let left_focal_point = opencv::core::Point2f { x: 100.0, y: 500.0 };
+        assert_eq!(
+            get_focal_crop_rect(original_size, resized_size, target_size, left_focal_point),
+            opencv::core::Rect { x: 0, y: 0, width: 200, height: 200 }
+        );
+
+        let right_focal_point = opencv::core::Point2f { x: 1900.0, y: 500.0 };
+        assert_eq!(
+            get_focal_crop_rect(original_size, resized_size, target_size, right_focal_point),
+            opencv::core::Rect { x: 200, y: 0, width: 200, height: 200 }
+        );
+    }
 }
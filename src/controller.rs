@@ -1,52 +1,174 @@
 use actix_web::http::header;
 use opencv::prelude::MatTraitConstManual; // to get method `.size()` must have this use
-use opencv::{core::{Mat}};
-use opencv::core::Vector;
-use crate::calc::{new_width_when_respect_aspect_ration, new_height_when_respect_aspect_ration};
+use opencv::core::Mat;
+use crate::calc;
+use crate::image::encoder::{self, EncodedImage, ImageFormat};
 use crate::image::image_manipulator;
-use crate::url_props::UrlPropsController;
-use crate::{security, url_props};
-use crate::settings::{Settings};
-use crate::{service::image::get_image};
-use actix_web::{get, web, Result, HttpRequest, HttpResponse};
-
-#[get("/{key}/{width:-?\\d+}x{height:-?\\d+}{smart:(/smart)?}{halign:(/(left|right|center))?}{valign:(/(top|middle|bottom))?}/{filename:.*}")]
-pub async fn file_cv(req: HttpRequest, path: web::Path<UrlPropsController>) -> Result<HttpResponse, actix_web::Error> {
-    Settings::start();
+use crate::image::smart::{self, DetectorRegistry};
+use crate::image::smart::cache::{self, detection_cache};
+use crate::error::ThumborError;
+use crate::filters::{self, FilterContext, FilterRegistry};
+use crate::{meta, security, url_parser};
+use crate::settings::{conf, Settings};
+use crate::service::image::{decode_image, get_image, SourceImage};
+use crate::service::processing_pool::processing_pool;
+use crate::url_props::UrlProps;
+use actix_web::{get, web, HttpRequest, HttpResponse};
 
-    let mut url_props = url_props::build_url_props(path.into_inner());
-    if !security::is_valid_key(req.uri().to_string()) {
-        return Ok(HttpResponse::Unauthorized().finish());
+/**
+ * Returns the digest the detection cache uses for the source. Hashing reads the whole file,
+ * so it is only done when the detectors run.
+ */
+fn get_source_digest(source: &SourceImage, url_props: &UrlProps) -> Option<String> {
+    match smart::needs_detection(url_props) && detection_cache().is_enabled() {
+        true => Some(cache::get_digest(&source.bytes)),
+        false => None,
     }
+}
 
-    let img = get_image(&url_props.filename).await;
-    let original_size = img.image.size().unwrap();
+/**
+ * Runs every CPU-bound step of the request: trim, crop, resize, flips, filters and encode.
+ * The crop is centred on the `focal()` rectangles or, with `smart`, on the focal points of the
+ * first detector that finds something, cached per source. Otherwise it follows the alignment. With `debug`, the
+ * focal points are drawn on the output.
+ * The kept source metadata is embedded unless the `strip_exif()` or `strip_icc()` filters remove it.
+ * The output keeps the source format unless the `format()` filter or the format
+ * negotiated from the `Accept` header changes it. Animated GIFs are never negotiated, and
+ * formats OpenCV cannot write, like GIF before OpenCV 4.11, are encoded as PNG.
+ */
+fn process_image(
+    source: SourceImage,
+    mut url_props: UrlProps,
+    filter_registry: FilterRegistry,
+    detector_registry: DetectorRegistry,
+    negotiated_format: Option<ImageFormat>,
+) -> Result<EncodedImage, ThumborError> {
+    let source_digest = get_source_digest(&source, &url_props);
+    let mut img = decode_image(source)?;
 
-    if url_props.width == 0 {
-        url_props.width = new_width_when_respect_aspect_ration(original_size.width, original_size.height, url_props.height);
-    }
+    let region = image_manipulator::get_source_region(&img.image, &url_props)?;
+    let origin = region.origin;
+    img.image = region.image;
+    let original_size = img.image.size()?;
+
+    let target_size = calc::get_url_target_size(original_size, &url_props, url_props.allow_upscale());
+    url_props.width = target_size.width;
+    url_props.height = target_size.height;
 
-    if url_props.height == 0 {
-        url_props.height = new_height_when_respect_aspect_ration(original_size.width, original_size.height, url_props.width);
+    let focal_points = smart::get_focal_points(&img.image, &url_props, origin, &detector_registry, source_digest.as_deref())?;
+    if url_props.debug {
+        img.image = smart::draw_focal_points(&img.image, &focal_points)?;
     }
 
     let mut final_image: Mat;
-    let resized_image = image_manipulator::resize(&img, &url_props);
-    final_image = image_manipulator::crop(&resized_image, &url_props, original_size);
+    let resized_image = image_manipulator::resize(&img, &url_props)?;
+    final_image = match url_props.fit_in {
+        Some(_) => resized_image,
+        None => image_manipulator::crop(&resized_image, &url_props, original_size, &focal_points)?,
+    };
 
     if url_props.flip.horizontal {
-        final_image = image_manipulator::flip_horizontal(&final_image);
+        final_image = image_manipulator::flip_horizontal(&final_image)?;
     }
 
     if url_props.flip.vertical {
-        final_image = image_manipulator::flip_vertical(&final_image);
+        final_image = image_manipulator::flip_vertical(&final_image)?;
     }
 
-    let mut out_vector: Vector<u8> = Vector::new();
-    opencv::imgcodecs::imencode(".jpg", &final_image, &mut out_vector, &Vector::new()).expect("Encode image");
+    let mut filter_context = FilterContext::new(final_image);
+    filter_context.encode_options.metadata = img.metadata;
+    filter_registry.apply(&url_props.filters, &mut filter_context)?;
 
-    Ok(HttpResponse::Ok()
-        .content_type(img.mime_type.as_str())
-        .insert_header(header::CacheControl(vec![header::CacheDirective::MaxAge(3600)]))
-        .body(out_vector.to_vec()))
+    let format = filter_context.format
+        .or(negotiated_format.filter(|_| img.format != ImageFormat::Gif))
+        .unwrap_or(img.format);
+    match filter_context.max_bytes {
+        Some(max_bytes) => encoder::encode_with_max_bytes(&filter_context.image, format, &filter_context.encode_options, max_bytes),
+        None => encoder::encode(&filter_context.image, format, &filter_context.encode_options),
+    }
+}
+
+#[get("/{key}/{path:.*}")]
+pub async fn file_cv(req: HttpRequest, path: web::Path<(String, String)>) -> Result<HttpResponse, ThumborError> {
+    Settings::start();
+
+    let (_key, path) = path.into_inner();
+    let url_props = url_parser::parse(&path)?;
+    if !security::is_valid_key(req.uri().to_string()) {
+        return Err(ThumborError::Forbidden("Invalid key".to_string()));
+    }
+
+    let filter_registry = filters::registry();
+    filter_registry.validate(&url_props.filters)?;
+    let detector_registry = smart::registry();
+    // The `smart_detectors` setting is validated at startup, only the `detectors()` filter is checked here
+    if url_props.alignment.smart && url_props.has_filter("detectors") {
+        detector_registry.validate(&smart::get_detector_names(&url_props))?;
+    }
+
+    let source = get_image(&url_props.filename, req.headers()).await?;
+
+    if url_props.meta {
+        let meta = processing_pool()
+            .run(move || {
+                let source_digest = get_source_digest(&source, &url_props);
+                meta::build_meta(&url_props, &decode_image(source)?.image, &detector_registry, source_digest.as_deref())
+            })
+            .await??;
+        return Ok(HttpResponse::Ok().json(meta));
+    }
+
+    // Urls that pin the format are the same for every browser
+    let auto_format = (conf().auto_webp || conf().auto_avif) && !url_props.has_filter("format");
+    let negotiated_format = match req.headers().get(header::ACCEPT).and_then(|accept| accept.to_str().ok()) {
+        Some(accept) if auto_format => encoder::negotiate_format(accept, conf().auto_webp, conf().auto_avif),
+        _ => None,
+    };
+
+    let encoded_image = processing_pool()
+        .run(move || process_image(source, url_props, filter_registry, detector_registry, negotiated_format))
+        .await??;
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(encoded_image.format.mime_type())
+        .insert_header(header::CacheControl(vec![header::CacheDirective::MaxAge(3600)]));
+    if auto_format {
+        response.insert_header((header::VARY, "Accept"));
+    }
+    if encoded_image.max_bytes_exceeded {
+        response.insert_header(("X-Max-Bytes-Exceeded", "true"));
+    }
+
+    Ok(response.body(encoded_image.bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    async fn get_status(uri: &str) -> StatusCode {
+        let app = test::init_service(App::new().service(file_cv)).await;
+        test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await.status()
+    }
+
+    #[actix_web::test]
+    async fn test_manual_crop() {
+        assert_eq!(get_status("/unsafe/0x0:100x50/sun.jpg").await, StatusCode::OK);
+        assert_eq!(get_status("/unsafe/meta/0x0:100x50/sun.jpg").await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_inverted_crop_box() {
+        assert_eq!(get_status("/unsafe/300x0:100x100/sun.jpg").await, StatusCode::BAD_REQUEST);
+        assert_eq!(get_status("/unsafe/meta/0x100:100x100/sun.jpg").await, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_crop_box_outside_of_the_image() {
+        assert_eq!(get_status("/unsafe/5000x5000:6000x6000/sun.jpg").await, StatusCode::BAD_REQUEST);
+        assert_eq!(get_status("/unsafe/meta/5000x5000:6000x6000/sun.jpg").await, StatusCode::BAD_REQUEST);
+    }
 }
@@ -0,0 +1,106 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::filters::FilterError;
+use crate::url_parser::ParseError;
+
+/**
+ * Errors of a request, each one mapped to a HTTP status code.
+ */
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ThumborError {
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Source not found: {0}")]
+    SourceNotFound(String),
+    #[error("Image too large: {0}")]
+    TooLarge(String),
+    #[error("Upstream error: {0}")]
+    Upstream(String),
+    #[error("Upstream timeout: {0}")]
+    UpstreamTimeout(String),
+    #[error("Could not decode image: {0}")]
+    Decode(String),
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+impl From<ParseError> for ThumborError {
+    fn from(error: ParseError) -> Self {
+        ThumborError::BadRequest(error.to_string())
+    }
+}
+
+impl From<FilterError> for ThumborError {
+    fn from(error: FilterError) -> Self {
+        match error {
+            FilterError::Failed { .. } => ThumborError::Internal(error.to_string()),
+            _ => ThumborError::BadRequest(error.to_string()),
+        }
+    }
+}
+
+impl From<opencv::Error> for ThumborError {
+    fn from(error: opencv::Error) -> Self {
+        ThumborError::Internal(error.to_string())
+    }
+}
+
+impl ResponseError for ThumborError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ThumborError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ThumborError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ThumborError::SourceNotFound(_) => StatusCode::NOT_FOUND,
+            ThumborError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ThumborError::Upstream(_) | ThumborError::Decode(_) => StatusCode::BAD_GATEWAY,
+            ThumborError::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ThumborError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ThumborError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({
+            "status": self.status_code().as_u16(),
+            "error": self.to_string(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    #[test]
+    fn test_status_codes() {
+        assert_eq!(ThumborError::BadRequest("".to_string()).status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(ThumborError::Forbidden("".to_string()).status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(ThumborError::SourceNotFound("".to_string()).status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(ThumborError::TooLarge("".to_string()).status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(ThumborError::Upstream("".to_string()).status_code(), StatusCode::BAD_GATEWAY);
+        assert_eq!(ThumborError::UpstreamTimeout("".to_string()).status_code(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(ThumborError::Decode("".to_string()).status_code(), StatusCode::BAD_GATEWAY);
+        assert_eq!(ThumborError::ServiceUnavailable("".to_string()).status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ThumborError::Internal("".to_string()).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_from_filter_error() {
+        assert_eq!(
+            ThumborError::from(FilterError::UnknownFilter("blur".to_string())),
+            ThumborError::BadRequest("Unknown filter: blur".to_string())
+        );
+        assert_eq!(
+            ThumborError::from(FilterError::Failed { name: "blur".to_string(), message: "oops".to_string() }),
+            ThumborError::Internal("Filter blur failed: oops".to_string())
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+use opencv::core::Mat;
+use thiserror::Error;
+
+use crate::image::encoder::{EncodeOptions, ImageFormat};
+use crate::registry::Registry;
+use crate::url_props::FilterCall;
+
+pub mod builtin;
+
+lazy_static! {
+    static ref REGISTRY: RwLock<FilterRegistry> = RwLock::new(FilterRegistry::with_builtin_filters());
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum FilterError {
+    #[error("Unknown filter: {0}")]
+    UnknownFilter(String),
+    #[error("Filter {name} expects from {min} to {max} arguments, got {received}")]
+    InvalidArity { name: String, min: usize, max: usize, received: usize },
+    #[error("Invalid argument {index} of filter {name}: {value}")]
+    InvalidArgument { name: String, index: usize, value: String },
+    #[error("Filter {name} failed: {message}")]
+    Failed { name: String, message: String },
+}
+
+/**
+ * State shared by the filters of a request. Filters can replace the image
+ * or change how it is encoded.
+ */
+pub struct FilterContext {
+    pub image: Mat,
+    pub format: Option<ImageFormat>,
+    pub encode_options: EncodeOptions,
+    pub max_bytes: Option<usize>,
+}
+
+impl FilterContext {
+    pub fn new(image: Mat) -> Self {
+        FilterContext {
+            image,
+            format: None,
+            encode_options: EncodeOptions::from_settings(),
+            max_bytes: None,
+        }
+    }
+}
+
+/**
+ * Arguments of a filter call, as written in the url.
+ */
+pub struct FilterArgs<'a> {
+    name: &'a str,
+    args: &'a [String],
+}
+
+impl<'a> FilterArgs<'a> {
+    pub fn new(name: &'a str, args: &'a [String]) -> Self {
+        FilterArgs { name, args }
+    }
+
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&'a str> {
+        self.args.get(index).map(|arg| arg.as_str())
+    }
+
+    /**
+     * Parses the argument at `index`, e.g. `args.parse::<i32>(0)`.
+     */
+    pub fn parse<T: FromStr>(&self, index: usize) -> Result<T, FilterError> {
+        let value = self.get(index).unwrap_or_default();
+        value.trim().parse::<T>().map_err(|_| self.invalid_argument(index))
+    }
+
+    pub fn invalid_argument(&self, index: usize) -> FilterError {
+        FilterError::InvalidArgument {
+            name: self.name.to_string(),
+            index,
+            value: self.get(index).unwrap_or_default().to_string(),
+        }
+    }
+
+    pub fn failed(&self, message: impl ToString) -> FilterError {
+        FilterError::Failed { name: self.name.to_string(), message: message.to_string() }
+    }
+}
+
+pub trait Filter: Send + Sync {
+    /**
+     * Accepted number of arguments, as `(min, max)`.
+     */
+    fn arity(&self) -> (usize, usize);
+
+    fn apply(&self, context: &mut FilterContext, args: &FilterArgs) -> Result<(), FilterError>;
+}
+
+pub type FilterRegistry = Registry<dyn Filter>;
+
+impl Registry<dyn Filter> {
+    pub fn with_builtin_filters() -> Self {
+        let mut registry = FilterRegistry::new();
+        builtin::register_builtin_filters(&mut registry);
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, filter: impl Filter + 'static) {
+        self.insert(name, Arc::new(filter));
+    }
+
+    fn find(&self, call: &FilterCall) -> Result<Arc<dyn Filter>, FilterError> {
+        let filter = self
+            .get(&call.name)
+            .ok_or_else(|| FilterError::UnknownFilter(call.name.clone()))?;
+
+        let (min, max) = filter.arity();
+        if call.args.len() < min || call.args.len() > max {
+            return Err(FilterError::InvalidArity {
+                name: call.name.clone(),
+                min,
+                max,
+                received: call.args.len(),
+            });
+        }
+
+        Ok(filter.clone())
+    }
+
+    /**
+     * Checks that every filter exists and receives a valid number of arguments.
+     */
+    pub fn validate(&self, calls: &[FilterCall]) -> Result<(), FilterError> {
+        for call in calls {
+            self.find(call)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Applies the filters in url order.
+     */
+    pub fn apply(&self, calls: &[FilterCall], context: &mut FilterContext) -> Result<(), FilterError> {
+        for call in calls {
+            let filter = self.find(call)?;
+            filter.apply(context, &FilterArgs::new(&call.name, &call.args))?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Adds a filter to the registry of the server, before it starts.
+ */
+pub fn register(name: &str, filter: impl Filter + 'static) {
+    REGISTRY.write().unwrap().register(name, filter);
+}
+
+pub fn registry() -> FilterRegistry {
+    REGISTRY.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+    use opencv::core::{Scalar, CV_8UC1};
+    use opencv::prelude::MatTraitConst;
+    use crate::image::metadata::ImageMetadata;
+
+    struct AddFilter;
+
+    impl Filter for AddFilter {
+        fn arity(&self) -> (usize, usize) {
+            (1, 1)
+        }
+
+        fn apply(&self, context: &mut FilterContext, args: &FilterArgs) -> Result<(), FilterError> {
+            let value = args.parse::<f64>(0)?;
+            let mut image = Mat::default();
+            context.image.convert_to(&mut image, -1, 1.0, value).map_err(|error| args.failed(error))?;
+            context.image = image;
+            Ok(())
+        }
+    }
+
+    struct MultiplyFilter;
+
+    impl Filter for MultiplyFilter {
+        fn arity(&self) -> (usize, usize) {
+            (0, 1)
+        }
+
+        fn apply(&self, context: &mut FilterContext, args: &FilterArgs) -> Result<(), FilterError> {
+            let value = if args.is_empty() { 2.0 } else { args.parse::<f64>(0)? };
+            let mut image = Mat::default();
+            context.image.convert_to(&mut image, -1, value, 0.0).map_err(|error| args.failed(error))?;
+            context.image = image;
+            Ok(())
+        }
+    }
+
+    fn call(name: &str, args: Vec<&str>) -> FilterCall {
+        FilterCall { name: name.to_string(), args: args.iter().map(|arg| arg.to_string()).collect() }
+    }
+
+    fn build_registry() -> FilterRegistry {
+        let mut registry = FilterRegistry::new();
+        registry.register("add", AddFilter);
+        registry.register("multiply", MultiplyFilter);
+        registry
+    }
+
+    fn pixel(image: &Mat) -> u8 {
+        *image.at_2d::<u8>(0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_apply_filters_in_url_order() {
+        let registry = build_registry();
+        let image = Mat::new_rows_cols_with_default(2, 2, CV_8UC1, Scalar::all(10.0)).unwrap();
+
+        let mut context = FilterContext::new(image.clone());
+        registry.apply(&[call("add", vec!["5"]), call("multiply", vec![])], &mut context).unwrap();
+        assert_eq!(pixel(&context.image), 30);
+
+        let mut context = FilterContext::new(image);
+        registry.apply(&[call("multiply", vec!["3"]), call("add", vec!["5"])], &mut context).unwrap();
+        assert_eq!(pixel(&context.image), 35);
+    }
+
+    #[test]
+    fn test_validate_unknown_filter() {
+        let registry = build_registry();
+        assert_eq!(
+            registry.validate(&[call("add", vec!["1"]), call("blur", vec!["1"])]),
+            Err(FilterError::UnknownFilter("blur".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_validate_invalid_arity() {
+        let registry = build_registry();
+        assert_eq!(
+            registry.validate(&[call("add", vec!["1", "2"])]),
+            Err(FilterError::InvalidArity { name: "add".to_string(), min: 1, max: 1, received: 2 })
+        );
+        assert_eq!(registry.validate(&[call("multiply", vec![]), call("multiply", vec!["2"])]), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_builtin_filters() {
+        let registry = FilterRegistry::with_builtin_filters();
+        assert_eq!(registry.validate(&[call("no_upscale", vec![])]), Ok(()));
+        assert_eq!(
+            registry.validate(&[call("no_upscale", vec!["1"])]),
+            Err(FilterError::InvalidArity { name: "no_upscale".to_string(), min: 0, max: 0, received: 1 })
+        );
+    }
+
+    #[test]
+    fn test_apply_format_filter() {
+        let registry = FilterRegistry::with_builtin_filters();
+        let mut context = FilterContext::new(Mat::default());
+
+        registry.apply(&[call("format", vec!["webp"])], &mut context).unwrap();
+        assert_eq!(context.format, Some(ImageFormat::Webp));
+
+        assert_eq!(
+            registry.apply(&[call("format", vec!["bmp"])], &mut context),
+            Err(FilterError::InvalidArgument { name: "format".to_string(), index: 0, value: "bmp".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_detectors_filter_arity() {
+        let registry = FilterRegistry::with_builtin_filters();
+        let detectors = ["face", "feature", "profile", "eyes"];
+        assert_eq!(registry.validate(&[call("detectors", detectors.to_vec())]), Ok(()));
+
+        let too_many = [&detectors[..], &["face"]].concat();
+        assert_eq!(
+            registry.validate(&[call("detectors", too_many)]),
+            Err(FilterError::InvalidArity { name: "detectors".to_string(), min: 1, max: 4, received: 5 })
+        );
+    }
+
+    #[test]
+    fn test_apply_quality_filter() {
+        let registry = FilterRegistry::with_builtin_filters();
+        let mut context = FilterContext::new(Mat::default());
+        context.encode_options.webp_quality = Some(90);
+
+        registry.apply(&[call("quality", vec!["40"])], &mut context).unwrap();
+        assert_eq!(context.encode_options.quality, Some(40));
+        assert_eq!(context.encode_options.webp_quality, Some(40));
+
+        assert_eq!(
+            registry.apply(&[call("quality", vec!["101"])], &mut context),
+            Err(FilterError::InvalidArgument { name: "quality".to_string(), index: 0, value: "101".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_apply_strip_filters() {
+        let registry = FilterRegistry::with_builtin_filters();
+        let mut context = FilterContext::new(Mat::default());
+        context.encode_options.metadata = ImageMetadata { exif: Some(vec![1]), icc: Some(vec![2]) };
+
+        registry.apply(&[call("strip_exif", vec![])], &mut context).unwrap();
+        assert_eq!(context.encode_options.metadata, ImageMetadata { exif: None, icc: Some(vec![2]) });
+
+        registry.apply(&[call("strip_icc", vec![])], &mut context).unwrap();
+        assert_eq!(context.encode_options.metadata, ImageMetadata::default());
+    }
+
+    #[test]
+    fn test_apply_focal_filter() {
+        let registry = FilterRegistry::with_builtin_filters();
+        let mut context = FilterContext::new(Mat::default());
+
+        registry.apply(&[call("focal", vec!["10x20:110x220"])], &mut context).unwrap();
+        assert_eq!(
+            registry.apply(&[call("focal", vec!["10x20"])], &mut context),
+            Err(FilterError::InvalidArgument { name: "focal".to_string(), index: 0, value: "10x20".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_apply_max_bytes_filter() {
+        let registry = FilterRegistry::with_builtin_filters();
+        let mut context = FilterContext::new(Mat::default());
+
+        registry.apply(&[call("max_bytes", vec!["35000"])], &mut context).unwrap();
+        assert_eq!(context.max_bytes, Some(35000));
+
+        assert_eq!(
+            registry.apply(&[call("max_bytes", vec!["0"])], &mut context),
+            Err(FilterError::InvalidArgument { name: "max_bytes".to_string(), index: 0, value: "0".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_apply_invalid_argument() {
+        let registry = build_registry();
+        let mut context = FilterContext::new(Mat::new_rows_cols_with_default(2, 2, CV_8UC1, Scalar::all(10.0)).unwrap());
+        assert_eq!(
+            registry.apply(&[call("add", vec!["ten"])], &mut context),
+            Err(FilterError::InvalidArgument { name: "add".to_string(), index: 0, value: "ten".to_string() })
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use super::{Filter, FilterArgs, FilterContext, FilterError, FilterRegistry};
+use crate::image::encoder::ImageFormat;
+use crate::image::smart;
+use crate::url_parser;
+
+/**
+ * `no_upscale()`: the image is not resized above its original size.
+ * Read by the resize step through `UrlProps::allow_upscale`, so it does nothing here.
+ */
+pub struct NoUpscale;
+
+impl Filter for NoUpscale {
+    fn arity(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    fn apply(&self, _context: &mut FilterContext, _args: &FilterArgs) -> Result<(), FilterError> {
+        Ok(())
+    }
+}
+
+/**
+ * `format(webp|png|jpeg|avif|gif)`: encodes the output in the format instead of the source one.
+ */
+pub struct Format;
+
+impl Filter for Format {
+    fn arity(&self) -> (usize, usize) {
+        (1, 1)
+    }
+
+    fn apply(&self, context: &mut FilterContext, args: &FilterArgs) -> Result<(), FilterError> {
+        let format = args.get(0).and_then(ImageFormat::from_name).ok_or_else(|| args.invalid_argument(0))?;
+        context.format = Some(format);
+        Ok(())
+    }
+}
+
+/**
+ * `quality(0-100)`: quality of the JPEG and WebP output, instead of the settings.
+ */
+pub struct Quality;
+
+impl Filter for Quality {
+    fn arity(&self) -> (usize, usize) {
+        (1, 1)
+    }
+
+    fn apply(&self, context: &mut FilterContext, args: &FilterArgs) -> Result<(), FilterError> {
+        let quality = args.parse::<i32>(0)?;
+        if !(0..=100).contains(&quality) {
+            return Err(args.invalid_argument(0));
+        }
+
+        context.encode_options.quality = Some(quality);
+        context.encode_options.webp_quality = Some(quality);
+        Ok(())
+    }
+}
+
+/**
+ * `max_bytes(n)`: lowers the quality, and then the dimensions, until the output fits in n bytes.
+ */
+pub struct MaxBytes;
+
+impl Filter for MaxBytes {
+    fn arity(&self) -> (usize, usize) {
+        (1, 1)
+    }
+
+    fn apply(&self, context: &mut FilterContext, args: &FilterArgs) -> Result<(), FilterError> {
+        let max_bytes = args.parse::<usize>(0)?;
+        if max_bytes == 0 {
+            return Err(args.invalid_argument(0));
+        }
+
+        context.max_bytes = Some(max_bytes);
+        Ok(())
+    }
+}
+
+/**
+ * `strip_exif()`: the output has no EXIF, even when the `preserve_exif` setting keeps it.
+ */
+pub struct StripExif;
+
+impl Filter for StripExif {
+    fn arity(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    fn apply(&self, context: &mut FilterContext, _args: &FilterArgs) -> Result<(), FilterError> {
+        context.encode_options.metadata.exif = None;
+        Ok(())
+    }
+}
+
+/**
+ * `strip_icc()`: the output has no ICC profile, even when the `preserve_icc` setting keeps it.
+ */
+pub struct StripIcc;
+
+impl Filter for StripIcc {
+    fn arity(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    fn apply(&self, context: &mut FilterContext, _args: &FilterArgs) -> Result<(), FilterError> {
+        context.encode_options.metadata.icc = None;
+        Ok(())
+    }
+}
+
+/**
+ * `focal(AxB:CxD)`: the crop keeps the rectangle, in source coordinates, as centred as possible.
+ * It can be repeated. Read by the crop step through `smart::get_url_focal_points`, so it only
+ * validates the rectangle here.
+ */
+pub struct Focal;
+
+impl Filter for Focal {
+    fn arity(&self) -> (usize, usize) {
+        (1, 1)
+    }
+
+    fn apply(&self, _context: &mut FilterContext, args: &FilterArgs) -> Result<(), FilterError> {
+        match url_parser::parse_focal_rect(args.get(0).unwrap_or_default()) {
+            Some(_) => Ok(()),
+            None => Err(args.invalid_argument(0)),
+        }
+    }
+}
+
+/**
+ * `detectors(face,feature,...)`: the smart detectors of the request, in order, instead of the
+ * `smart_detectors` setting. Read by the crop step through `smart::get_detector_names`.
+ * Each registered detector can be listed once, so there are at most as many arguments.
+ */
+pub struct Detectors;
+
+impl Filter for Detectors {
+    fn arity(&self) -> (usize, usize) {
+        (1, smart::registry().len())
+    }
+
+    fn apply(&self, _context: &mut FilterContext, _args: &FilterArgs) -> Result<(), FilterError> {
+        Ok(())
+    }
+}
+
+pub fn register_builtin_filters(registry: &mut FilterRegistry) {
+    registry.register("no_upscale", NoUpscale);
+    registry.register("format", Format);
+    registry.register("quality", Quality);
+    registry.register("max_bytes", MaxBytes);
+    registry.register("strip_exif", StripExif);
+    registry.register("strip_icc", StripIcc);
+    registry.register("focal", Focal);
+    registry.register("detectors", Detectors);
+}
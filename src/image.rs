@@ -1 +1,4 @@
 pub mod image_manipulator;
+pub mod encoder;
+pub mod metadata;
+pub mod smart;
@@ -0,0 +1,521 @@
+use opencv::core::{Mat, Size, Vector};
+use opencv::prelude::MatTraitConst;
+use opencv::prelude::MatTraitConstManual; // to get method `.size()` must have this use
+
+use crate::error::ThumborError;
+use crate::image::image_manipulator;
+use crate::image::metadata::{self, ImageMetadata};
+use crate::settings::{conf, Settings};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+    Gif,
+}
+
+impl ImageFormat {
+    /**
+     * Parses the name used by the `format()` filter.
+     */
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name.trim().to_lowercase().as_str() {
+            "jpeg" | "jpg" => Some(ImageFormat::Jpeg),
+            "png" => Some(ImageFormat::Png),
+            "webp" => Some(ImageFormat::Webp),
+            "avif" => Some(ImageFormat::Avif),
+            "gif" => Some(ImageFormat::Gif),
+            _ => None,
+        }
+    }
+
+    pub fn from_mime_type(mime_type: &str) -> Option<ImageFormat> {
+        match mime_type.split(';').next().unwrap_or_default().trim() {
+            "image/jpeg" | "image/jpg" => Some(ImageFormat::Jpeg),
+            "image/png" => Some(ImageFormat::Png),
+            "image/webp" => Some(ImageFormat::Webp),
+            "image/avif" => Some(ImageFormat::Avif),
+            "image/gif" => Some(ImageFormat::Gif),
+            _ => None,
+        }
+    }
+
+    /**
+     * Detects the format from the first bytes of the file.
+     */
+    pub fn detect(bytes: &[u8]) -> Option<ImageFormat> {
+        if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            return Some(ImageFormat::Jpeg);
+        }
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            return Some(ImageFormat::Png);
+        }
+        if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            return Some(ImageFormat::Gif);
+        }
+        if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            return Some(ImageFormat::Webp);
+        }
+        if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" && (&bytes[8..12] == b"avif" || &bytes[8..12] == b"avis") {
+            return Some(ImageFormat::Avif);
+        }
+        None
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => ".jpg",
+            ImageFormat::Png => ".png",
+            ImageFormat::Webp => ".webp",
+            ImageFormat::Avif => ".avif",
+            ImageFormat::Gif => ".gif",
+        }
+    }
+
+    /**
+     * Returns whether OpenCV was built with an encoder for the format. GIF needs OpenCV 4.11
+     * and AVIF an optional library, so they are often missing.
+     */
+    pub fn can_encode(&self) -> bool {
+        opencv::imgcodecs::have_image_writer(self.extension()).unwrap_or(false)
+    }
+
+    /**
+     * Returns the format itself when OpenCV can encode it, otherwise PNG, which keeps the alpha.
+     */
+    pub fn or_encodable(self) -> ImageFormat {
+        match self.can_encode() {
+            true => self,
+            false => ImageFormat::Png,
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Gif => "image/gif",
+        }
+    }
+}
+
+/**
+ * Returns whether the `Accept` header value accepts the mime type, ignoring `q=0` entries.
+ */
+fn accepts(accept: &str, mime_type: &str) -> bool {
+    accept.split(',').any(|media_range| {
+        let mut parts = media_range.split(';').map(|part| part.trim());
+        let media_type = parts.next().unwrap_or_default();
+        let refused = parts.any(|parameter| {
+            parameter
+                .strip_prefix("q=")
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .map(|quality| quality <= 0.0)
+                .unwrap_or(false)
+        });
+        media_type.eq_ignore_ascii_case(mime_type) && !refused
+    })
+}
+
+/**
+ * Picks the best modern format the browser accepts, AVIF first and then WebP,
+ * when they are enabled by the `auto_avif` and `auto_webp` settings and OpenCV can encode them.
+ */
+pub fn negotiate_format(accept: &str, auto_webp: bool, auto_avif: bool) -> Option<ImageFormat> {
+    if auto_avif && accepts(accept, ImageFormat::Avif.mime_type()) && ImageFormat::Avif.can_encode() {
+        return Some(ImageFormat::Avif);
+    }
+    if auto_webp && accepts(accept, ImageFormat::Webp.mime_type()) && ImageFormat::Webp.can_encode() {
+        return Some(ImageFormat::Webp);
+    }
+    None
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EncodeOptions {
+    pub quality: Option<i32>,
+    pub webp_quality: Option<i32>,
+    pub progressive_jpeg: bool,
+    pub background_color: Option<[u8; 3]>,
+    pub metadata: ImageMetadata,
+}
+
+/**
+ * Parses a `rrggbb` colour, with or without the leading `#`.
+ */
+pub fn parse_hex_color(color: &str) -> Option<[u8; 3]> {
+    let color = color.trim().trim_start_matches('#');
+    if color.len() != 6 || !color.is_ascii() {
+        return None;
+    }
+
+    let mut rgb = [0u8; 3];
+    for (index, component) in rgb.iter_mut().enumerate() {
+        *component = u8::from_str_radix(&color[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(rgb)
+}
+
+impl EncodeOptions {
+    /**
+     * Options from the `quality`, `webp_quality`, `progressive_jpeg` and `background_color` settings.
+     */
+    pub fn from_settings() -> Self {
+        let conf = conf();
+        EncodeOptions {
+            quality: Some(conf.quality).filter(|quality| *quality > 0),
+            webp_quality: conf.webp_quality,
+            progressive_jpeg: conf.progressive_jpeg,
+            background_color: parse_hex_color(&conf.background_color),
+            metadata: ImageMetadata::default(),
+        }
+    }
+
+    /**
+     * Checks the `background_color` setting, which `from_settings` would otherwise replace with white.
+     * It runs once at startup.
+     */
+    pub fn validate_settings(settings: &Settings) -> Result<(), ThumborError> {
+        match parse_hex_color(&settings.background_color) {
+            Some(_) => Ok(()),
+            None => Err(ThumborError::Internal(format!("Invalid background_color: {}", settings.background_color))),
+        }
+    }
+
+    /**
+     * Colour used under transparent pixels when the format has no alpha. White by default.
+     */
+    pub fn background_color(&self) -> [u8; 3] {
+        self.background_color.unwrap_or([255, 255, 255])
+    }
+
+    /**
+     * Returns the `IMWRITE_*` params for the format. Without quality, the OpenCV default is used.
+     */
+    pub fn params(&self, format: ImageFormat) -> Vector<i32> {
+        let mut params: Vec<i32> = vec![];
+        match format {
+            ImageFormat::Jpeg => {
+                if let Some(quality) = self.quality {
+                    params.extend([opencv::imgcodecs::IMWRITE_JPEG_QUALITY, quality]);
+                }
+                if self.progressive_jpeg {
+                    params.extend([opencv::imgcodecs::IMWRITE_JPEG_PROGRESSIVE, 1]);
+                }
+            }
+            ImageFormat::Webp => {
+                if let Some(quality) = self.webp_quality.or(self.quality) {
+                    params.extend([opencv::imgcodecs::IMWRITE_WEBP_QUALITY, quality.max(1)]);
+                }
+            }
+            ImageFormat::Png | ImageFormat::Avif | ImageFormat::Gif => {}
+        }
+        Vector::from(params)
+    }
+}
+
+pub struct EncodedImage {
+    pub format: ImageFormat,
+    pub bytes: Vec<u8>,
+    pub max_bytes_exceeded: bool,
+}
+
+// Steps used by `encode_with_max_bytes`
+const MAX_BYTES_QUALITY_STEP: i32 = 10;
+const MAX_BYTES_MIN_QUALITY: i32 = 10;
+const MAX_BYTES_SCALE_STEP: f64 = 0.75;
+const MAX_BYTES_MIN_SIDE: i32 = 16;
+
+/**
+ * Encodes the image to the format, or to PNG when OpenCV was built without its encoder, so the
+ * format of the result may differ from the requested one.
+ * Images with alpha are flattened onto the background colour for JPEG, other formats keep it.
+ * The EXIF and ICC profile of the options are embedded in JPEG, PNG and WebP files.
+ */
+pub fn encode(image: &Mat, format: ImageFormat, options: &EncodeOptions) -> Result<EncodedImage, ThumborError> {
+    let format = format.or_encodable();
+    let flattened_image;
+    let image = if format == ImageFormat::Jpeg && image.channels() == 4 {
+        flattened_image = image_manipulator::flatten(image, options.background_color())?;
+        &flattened_image
+    } else {
+        image
+    };
+
+    let mut out_vector: Vector<u8> = Vector::new();
+    let encoded = opencv::imgcodecs::imencode(format.extension(), image, &mut out_vector, &options.params(format))
+        .map_err(|error| ThumborError::Internal(format!("Could not encode {}: {}", format.mime_type(), error)))?;
+    if !encoded {
+        return Err(ThumborError::Internal(format!("Could not encode {}", format.mime_type())));
+    }
+
+    let bytes = metadata::embed(out_vector.to_vec(), format, &options.metadata, image.size()?)?;
+    Ok(EncodedImage { format, bytes, max_bytes_exceeded: false })
+}
+
+/**
+ * Quality the image was first encoded with, where the search for `max_bytes` starts.
+ */
+fn get_max_bytes_start_quality(format: ImageFormat, options: &EncodeOptions) -> i32 {
+    let quality = match format {
+        ImageFormat::Webp => options.webp_quality.or(options.quality),
+        _ => options.quality,
+    };
+    quality.unwrap_or(95)
+}
+
+/**
+ * Encodes the image lowering the quality, and then the dimensions, until it fits in `max_bytes`.
+ * When it never fits, the smallest result is returned with `max_bytes_exceeded`.
+ */
+pub fn encode_with_max_bytes(image: &Mat, format: ImageFormat, options: &EncodeOptions, max_bytes: usize) -> Result<EncodedImage, ThumborError> {
+    let format = format.or_encodable();
+    let mut smallest = encode(image, format, options)?;
+    if smallest.bytes.len() <= max_bytes {
+        return Ok(smallest);
+    }
+
+    let mut options = options.clone();
+    if matches!(format, ImageFormat::Jpeg | ImageFormat::Webp) {
+        let mut quality = get_max_bytes_start_quality(format, &options);
+        while quality > MAX_BYTES_MIN_QUALITY {
+            quality = (quality - MAX_BYTES_QUALITY_STEP).max(MAX_BYTES_MIN_QUALITY);
+            options.quality = Some(quality);
+            options.webp_quality = Some(quality);
+
+            let encoded = encode(image, format, &options)?;
+            if encoded.bytes.len() <= max_bytes {
+                return Ok(encoded);
+            }
+            if encoded.bytes.len() < smallest.bytes.len() {
+                smallest = encoded;
+            }
+        }
+    }
+
+    let mut resized_image = image.clone();
+    loop {
+        let size = resized_image.size()?;
+        let new_size = Size {
+            width: (size.width as f64 * MAX_BYTES_SCALE_STEP) as i32,
+            height: (size.height as f64 * MAX_BYTES_SCALE_STEP) as i32,
+        };
+        if new_size.width < MAX_BYTES_MIN_SIDE || new_size.height < MAX_BYTES_MIN_SIDE {
+            break;
+        }
+
+        let mut smaller_image = Mat::default();
+        opencv::imgproc::resize(&resized_image, &mut smaller_image, new_size, 0.0, 0.0, opencv::imgproc::INTER_AREA)?;
+        resized_image = smaller_image;
+
+        let encoded = encode(&resized_image, format, &options)?;
+        if encoded.bytes.len() <= max_bytes {
+            return Ok(encoded);
+        }
+        if encoded.bytes.len() < smallest.bytes.len() {
+            smallest = encoded;
+        }
+    }
+
+    smallest.max_bytes_exceeded = true;
+    Ok(smallest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+    use opencv::core::{Scalar, CV_8UC3, CV_8UC4};
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(ImageFormat::from_name("jpeg"), Some(ImageFormat::Jpeg));
+        assert_eq!(ImageFormat::from_name("jpg"), Some(ImageFormat::Jpeg));
+        assert_eq!(ImageFormat::from_name("WEBP"), Some(ImageFormat::Webp));
+        assert_eq!(ImageFormat::from_name("avif"), Some(ImageFormat::Avif));
+        assert_eq!(ImageFormat::from_name("bmp"), None);
+    }
+
+    #[test]
+    fn test_from_mime_type() {
+        assert_eq!(ImageFormat::from_mime_type("image/png"), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_mime_type("image/gif; charset=binary"), Some(ImageFormat::Gif));
+        assert_eq!(ImageFormat::from_mime_type("type/jpeg"), None);
+    }
+
+    #[test]
+    fn test_detect() {
+        let jpeg = std::fs::read("./src/images/big.jpg").unwrap();
+        assert_eq!(ImageFormat::detect(&jpeg), Some(ImageFormat::Jpeg));
+        assert_eq!(ImageFormat::detect(b"\x89PNG\r\n\x1a\n...."), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::detect(b"RIFF\x00\x00\x00\x00WEBPVP8 "), Some(ImageFormat::Webp));
+        assert_eq!(ImageFormat::detect(b"\x00\x00\x00\x1cftypavif"), Some(ImageFormat::Avif));
+        assert_eq!(ImageFormat::detect(b"GIF89a"), Some(ImageFormat::Gif));
+        assert_eq!(ImageFormat::detect(b"plain text"), None);
+    }
+
+    #[test]
+    fn test_negotiate_format() {
+        let accept = "image/avif,image/webp,image/apng,image/*,*/*;q=0.8";
+        assert_eq!(negotiate_format(accept, true, false), Some(ImageFormat::Webp));
+        assert_eq!(negotiate_format(accept, false, false), None);
+        assert_eq!(negotiate_format("image/webp", false, true), None);
+        assert_eq!(negotiate_format("image/avif;q=0, image/webp;q=0.5", true, true), Some(ImageFormat::Webp));
+        assert_eq!(negotiate_format("text/html,*/*", true, true), None);
+    }
+
+    #[test]
+    fn test_negotiate_avif_only_with_encoder() {
+        // Most OpenCV builds have no AVIF encoder, browsers that accept it get WebP instead
+        let accept = "image/avif,image/webp,*/*;q=0.8";
+        let has_avif_writer = opencv::imgcodecs::have_image_writer(".avif").unwrap();
+        let expected_format = if has_avif_writer { ImageFormat::Avif } else { ImageFormat::Webp };
+        assert_eq!(negotiate_format(accept, true, true), Some(expected_format));
+        assert_eq!(negotiate_format("image/avif", false, true), Some(ImageFormat::Avif).filter(|_| has_avif_writer));
+
+        let negotiated_format = negotiate_format(accept, true, true).unwrap();
+        let image = Mat::new_rows_cols_with_default(10, 10, CV_8UC3, Scalar::all(128.0)).unwrap();
+        let encoded = encode(&image, negotiated_format, &EncodeOptions::default()).unwrap();
+        assert_eq!(encoded.format, negotiated_format);
+    }
+
+    #[test]
+    fn test_encode_options_params() {
+        let options = EncodeOptions { quality: Some(80), progressive_jpeg: true, ..Default::default() };
+        assert_eq!(options.params(ImageFormat::Jpeg).to_vec(), vec![
+            opencv::imgcodecs::IMWRITE_JPEG_QUALITY, 80,
+            opencv::imgcodecs::IMWRITE_JPEG_PROGRESSIVE, 1,
+        ]);
+        assert_eq!(options.params(ImageFormat::Webp).to_vec(), vec![opencv::imgcodecs::IMWRITE_WEBP_QUALITY, 80]);
+        assert_eq!(options.params(ImageFormat::Png).to_vec(), Vec::<i32>::new());
+
+        let options = EncodeOptions { webp_quality: Some(60), ..Default::default() };
+        assert_eq!(options.params(ImageFormat::Jpeg).to_vec(), Vec::<i32>::new());
+        assert_eq!(options.params(ImageFormat::Webp).to_vec(), vec![opencv::imgcodecs::IMWRITE_WEBP_QUALITY, 60]);
+    }
+
+    #[test]
+    fn test_encode_with_lower_quality_is_smaller() {
+        let image = opencv::imgcodecs::imread("./src/images/sun.jpg", opencv::imgcodecs::IMREAD_COLOR).unwrap();
+        let high = EncodeOptions { quality: Some(95), ..Default::default() };
+        let low = EncodeOptions { quality: Some(20), ..Default::default() };
+
+        let high_bytes = encode(&image, ImageFormat::Jpeg, &high).unwrap().bytes;
+        let low_bytes = encode(&image, ImageFormat::Jpeg, &low).unwrap().bytes;
+        assert!(low_bytes.len() < high_bytes.len());
+    }
+
+    #[test]
+    fn test_encode_with_max_bytes() {
+        let image = opencv::imgcodecs::imread("./src/images/big.jpg", opencv::imgcodecs::IMREAD_COLOR).unwrap();
+        let options = EncodeOptions { quality: Some(95), ..Default::default() };
+        let full_size = encode(&image, ImageFormat::Jpeg, &options).unwrap().bytes.len();
+
+        let encoded = encode_with_max_bytes(&image, ImageFormat::Jpeg, &options, full_size / 4).unwrap();
+        assert!(encoded.bytes.len() <= full_size / 4);
+        assert!(!encoded.max_bytes_exceeded);
+
+        let encoded = encode_with_max_bytes(&image, ImageFormat::Png, &options, full_size / 4).unwrap();
+        assert!(encoded.bytes.len() <= full_size / 4);
+        assert!(!encoded.max_bytes_exceeded);
+    }
+
+    #[test]
+    fn test_encode_with_max_bytes_starts_from_webp_quality() {
+        let options = EncodeOptions { quality: Some(80), webp_quality: Some(60), ..Default::default() };
+        assert_eq!(get_max_bytes_start_quality(ImageFormat::Webp, &options), 60);
+        assert_eq!(get_max_bytes_start_quality(ImageFormat::Jpeg, &options), 80);
+        assert_eq!(get_max_bytes_start_quality(ImageFormat::Webp, &EncodeOptions::default()), 95);
+
+        // Starting from 95 would try 25 before 20, and return it
+        let image = opencv::imgcodecs::imread("./src/images/sun.jpg", opencv::imgcodecs::IMREAD_COLOR).unwrap();
+        let options = EncodeOptions { webp_quality: Some(30), ..Default::default() };
+        let webp = |quality: i32| encode(&image, ImageFormat::Webp, &EncodeOptions { webp_quality: Some(quality), ..Default::default() }).unwrap();
+        let encoded = encode_with_max_bytes(&image, ImageFormat::Webp, &options, webp(25).bytes.len()).unwrap();
+        assert_eq!(encoded.bytes, webp(20).bytes);
+    }
+
+    #[test]
+    fn test_encode_with_max_bytes_that_never_fits() {
+        let image = opencv::imgcodecs::imread("./src/images/sun.jpg", opencv::imgcodecs::IMREAD_COLOR).unwrap();
+        let encoded = encode_with_max_bytes(&image, ImageFormat::Jpeg, &EncodeOptions::default(), 10).unwrap();
+        assert!(encoded.max_bytes_exceeded);
+        assert_eq!(ImageFormat::detect(&encoded.bytes), Some(ImageFormat::Jpeg));
+    }
+
+    #[test]
+    fn test_parse_hex_color() {
+        assert_eq!(parse_hex_color("ffffff"), Some([255, 255, 255]));
+        assert_eq!(parse_hex_color("#FF8000"), Some([255, 128, 0]));
+        assert_eq!(parse_hex_color("fff"), None);
+        assert_eq!(parse_hex_color("gggggg"), None);
+    }
+
+    #[test]
+    fn test_validate_settings() {
+        let settings = |background_color: &str| Settings { background_color: background_color.to_string(), ..Default::default() };
+        assert_eq!(EncodeOptions::validate_settings(&settings("#ff8000")), Ok(()));
+        assert_eq!(
+            EncodeOptions::validate_settings(&settings("orange")),
+            Err(ThumborError::Internal("Invalid background_color: orange".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_encode_keeps_alpha() {
+        // Fully transparent blue
+        let image = Mat::new_rows_cols_with_default(10, 10, CV_8UC4, Scalar::new(255.0, 0.0, 0.0, 0.0)).unwrap();
+
+        let encoded = encode(&image, ImageFormat::Png, &EncodeOptions::default()).unwrap();
+        let decoded = opencv::imgcodecs::imdecode(&Mat::from_slice(&encoded.bytes).unwrap(), opencv::imgcodecs::IMREAD_UNCHANGED).unwrap();
+        assert_eq!(decoded.channels(), 4);
+        assert_eq!(*decoded.at_2d::<opencv::core::Vec4b>(5, 5).unwrap(), opencv::core::Vec4b::from([255, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_encode_jpeg_flattens_alpha() {
+        let image = Mat::new_rows_cols_with_default(10, 10, CV_8UC4, Scalar::new(255.0, 0.0, 0.0, 0.0)).unwrap();
+        let options = EncodeOptions { background_color: Some([0, 255, 0]), ..Default::default() };
+
+        let encoded = encode(&image, ImageFormat::Jpeg, &options).unwrap();
+        let decoded = opencv::imgcodecs::imdecode(&Mat::from_slice(&encoded.bytes).unwrap(), opencv::imgcodecs::IMREAD_UNCHANGED).unwrap();
+        assert_eq!(decoded.channels(), 3);
+
+        let pixel = decoded.at_2d::<opencv::core::Vec3b>(5, 5).unwrap();
+        assert!(pixel[0] < 10 && pixel[1] > 245 && pixel[2] < 10);
+    }
+
+    #[test]
+    fn test_encode_matches_format() {
+        let image = Mat::new_rows_cols_with_default(10, 10, CV_8UC3, Scalar::all(128.0)).unwrap();
+
+        for format in [ImageFormat::Jpeg, ImageFormat::Png] {
+            let encoded = encode(&image, format, &EncodeOptions::default()).unwrap();
+            assert_eq!(encoded.format, format);
+            assert_eq!(ImageFormat::detect(&encoded.bytes), Some(format));
+        }
+    }
+
+    #[test]
+    fn test_encode_gif_source() {
+        // GIF sources keep their format, unless OpenCV is older than 4.11 and cannot write it
+        let image = Mat::new_rows_cols_with_default(10, 10, CV_8UC3, Scalar::all(128.0)).unwrap();
+        let expected_format = match opencv::imgcodecs::have_image_writer(".gif").unwrap() {
+            true => ImageFormat::Gif,
+            false => ImageFormat::Png,
+        };
+        assert_eq!(ImageFormat::Gif.or_encodable(), expected_format);
+
+        let encoded = encode(&image, ImageFormat::Gif, &EncodeOptions::default()).unwrap();
+        assert_eq!(encoded.format, expected_format);
+        assert_eq!(ImageFormat::detect(&encoded.bytes), Some(expected_format));
+
+        let encoded = encode_with_max_bytes(&image, ImageFormat::Gif, &EncodeOptions::default(), 10).unwrap();
+        assert_eq!(encoded.format, expected_format);
+        assert_eq!(ImageFormat::Jpeg.or_encodable(), ImageFormat::Jpeg);
+    }
+}
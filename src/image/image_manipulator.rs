@@ -1,36 +1,189 @@
-use opencv::core::{Mat, Size};
+use opencv::core::{Mat, Point, Rect, Scalar, Size, Vector};
+use opencv::prelude::MatTraitConst;
 use opencv::prelude::MatTraitConstManual; // to get method `.size()` must have this use
 
-use crate::{url_props::{UrlProps}, service::image::ImageWithType, calc};
+use crate::{url_props::{UrlProps, Trim, TrimCorner}, service::image::ImageWithType, calc};
+use crate::error::ThumborError;
+use crate::image::metadata::Orientation;
+use crate::image::smart::{self, FocalPoint};
 
 pub mod direction {
     pub const HORIZONTAL: i32 = 1;
     pub const VERTICAL: i32 = 0;
 }
 
-pub fn flip(image: &Mat, direction: i32) -> Mat {
+pub fn flip(image: &Mat, direction: i32) -> Result<Mat, ThumborError> {
     let mut flipped_image = Mat::default();
-    opencv::core::flip(&image, &mut flipped_image, direction).unwrap();
-    flipped_image
+    opencv::core::flip(&image, &mut flipped_image, direction)?;
+    Ok(flipped_image)
 }
 
-pub fn flip_horizontal(image: &Mat) -> Mat {
+pub fn flip_horizontal(image: &Mat) -> Result<Mat, ThumborError> {
     flip(image, direction::HORIZONTAL)
 }
 
-pub fn flip_vertical(image: &Mat) -> Mat {
+pub fn flip_vertical(image: &Mat) -> Result<Mat, ThumborError> {
     flip(image, direction::VERTICAL)
 }
 
-pub fn resize(img: &ImageWithType, url_props: &UrlProps) -> Mat {
-    let original_size = img.image.size().unwrap();
-    let new_aspect = calc::get_new_size_respecting_aspect_ratio(
-        original_size,
-        Size {
-            width: url_props.width,
-            height: url_props.height,
+/**
+ * Rotates or mirrors the image as stored in the file so it is shown upright.
+ */
+pub fn orient(image: &Mat, orientation: Orientation) -> Result<Mat, ThumborError> {
+    let rotate = |image: &Mat, rotate_code: i32| -> Result<Mat, ThumborError> {
+        let mut rotated_image = Mat::default();
+        opencv::core::rotate(image, &mut rotated_image, rotate_code)?;
+        Ok(rotated_image)
+    };
+
+    match orientation {
+        Orientation::Normal => Ok(image.clone()),
+        Orientation::FlipHorizontal => flip_horizontal(image),
+        Orientation::Rotate180 => rotate(image, opencv::core::ROTATE_180),
+        Orientation::FlipVertical => flip_vertical(image),
+        Orientation::Transpose => {
+            let mut transposed_image = Mat::default();
+            opencv::core::transpose(image, &mut transposed_image)?;
+            Ok(transposed_image)
         }
-    );
+        Orientation::Rotate90 => rotate(image, opencv::core::ROTATE_90_CLOCKWISE),
+        Orientation::Transverse => flip_horizontal(&rotate(image, opencv::core::ROTATE_90_CLOCKWISE)?),
+        Orientation::Rotate270 => rotate(image, opencv::core::ROTATE_90_COUNTERCLOCKWISE),
+    }
+}
+
+/**
+ * Blends a BGRA image onto an opaque background, given as RGB. Used for formats without alpha.
+ */
+pub fn flatten(image: &Mat, background_color: [u8; 3]) -> Result<Mat, ThumborError> {
+    let mut channels: Vector<Mat> = Vector::new();
+    opencv::core::split(image, &mut channels)?;
+
+    let mut alpha = Mat::default();
+    channels.get(3)?.convert_to(&mut alpha, opencv::core::CV_32F, 1.0 / 255.0, 0.0)?;
+
+    // Each channel is `(colour - background) * alpha + background`
+    let mut flattened_channels: Vector<Mat> = Vector::new();
+    for (index, background) in background_color.iter().rev().enumerate() {
+        let background = *background as f64;
+        let mut difference = Mat::default();
+        channels.get(index)?.convert_to(&mut difference, opencv::core::CV_32F, 1.0, -background)?;
+
+        let mut weighted = Mat::default();
+        opencv::core::multiply(&difference, &alpha, &mut weighted, 1.0, -1)?;
+
+        let mut flattened_channel = Mat::default();
+        weighted.convert_to(&mut flattened_channel, opencv::core::CV_8U, 1.0, background)?;
+        flattened_channels.push(flattened_channel);
+    }
+
+    let mut flattened_image = Mat::default();
+    opencv::core::merge(&flattened_channels, &mut flattened_image)?;
+    Ok(flattened_image)
+}
+
+/**
+ * Returns the rectangle left after removing the borders with the same colour as the
+ * corner pixel (top left by default). A pixel belongs to the border when the euclidean
+ * distance between its colour and the corner colour is not greater than the tolerance.
+ * Returns `None` when the whole image has the corner colour.
+ */
+pub fn get_trim_rect(image: &Mat, trim: &Trim) -> Result<Option<Rect>, ThumborError> {
+    let size = image.size()?;
+    let (x, y) = match trim.corner.unwrap_or(TrimCorner::TopLeft) {
+        TrimCorner::TopLeft => (0, 0),
+        TrimCorner::BottomRight => (size.width - 1, size.height - 1),
+    };
+    let tolerance = trim.tolerance.unwrap_or(0) as f64;
+
+    let corner_pixel = Mat::roi(image, Rect { x, y, width: 1, height: 1 })?;
+    let corner_color = opencv::core::mean(&corner_pixel, &Mat::default())?;
+    let corner_image = Mat::new_size_with_default(size, image.typ(), corner_color)?;
+
+    let mut difference = Mat::default();
+    opencv::core::absdiff(image, &corner_image, &mut difference)?;
+    let mut difference_float = Mat::default();
+    difference.convert_to(&mut difference_float, opencv::core::CV_32F, 1.0, 0.0)?;
+    let mut squared_difference = Mat::default();
+    opencv::core::multiply(&difference_float, &difference_float, &mut squared_difference, 1.0, -1)?;
+
+    // Sums the squared difference of every channel into a single channel
+    let channels_sum = Mat::new_rows_cols_with_default(1, image.channels(), opencv::core::CV_32F, Scalar::all(1.0))?;
+    let mut squared_distance = Mat::default();
+    opencv::core::transform(&squared_difference, &mut squared_distance, &channels_sum)?;
+
+    let mut content_mask = Mat::default();
+    opencv::imgproc::threshold(&squared_distance, &mut content_mask, tolerance * tolerance, 255.0, opencv::imgproc::THRESH_BINARY)?;
+    let mut content_mask_u8 = Mat::default();
+    content_mask.convert_to(&mut content_mask_u8, opencv::core::CV_8U, 1.0, 0.0)?;
+
+    let mut content_points = Mat::default();
+    opencv::core::find_non_zero(&content_mask_u8, &mut content_points)?;
+    if content_points.empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(opencv::imgproc::bounding_rect(&content_points)?))
+}
+
+/**
+ * Part of the source kept by the trim and the manual crop, before the resize.
+ */
+pub struct SourceRegion {
+    pub image: Mat,
+    // Top left corner in the source, the `focal()` rectangles are in source coordinates
+    pub origin: Point,
+    // Boxes cut in order, each relative to the image left by the step before
+    pub crops: Vec<Rect>,
+}
+
+/**
+ * Trims the borders, then cuts the manual crop box (`/AxB:CxD/`) clamped to what is left.
+ * The rendered image and the meta both start from this region. Fails with `BadRequest` when the
+ * crop box is completely outside of the image.
+ */
+pub fn get_source_region(image: &Mat, url_props: &UrlProps) -> Result<SourceRegion, ThumborError> {
+    let mut region = SourceRegion { image: image.clone(), origin: Point::default(), crops: vec![] };
+    let trim_rect = match &url_props.trim {
+        Some(trim) => get_trim_rect(image, trim)?,
+        None => None,
+    };
+    if let Some(rect) = trim_rect {
+        region.crop(rect)?;
+    }
+    if let Some(crop_box) = &url_props.crop {
+        let rect = calc::get_crop_rect(crop_box, region.image.size()?).ok_or_else(|| {
+            let crop_box = format!("{}x{}:{}x{}", crop_box.left, crop_box.top, crop_box.right, crop_box.bottom);
+            ThumborError::BadRequest(format!("Crop box outside of the image: {}", crop_box))
+        })?;
+        region.crop(rect)?;
+    }
+    Ok(region)
+}
+
+impl SourceRegion {
+    fn crop(&mut self, rect: Rect) -> Result<(), ThumborError> {
+        self.image = Mat::roi(&self.image, rect)?;
+        self.origin.x += rect.x;
+        self.origin.y += rect.y;
+        self.crops.push(rect);
+        Ok(())
+    }
+}
+
+/**
+ * Returns the size the image is resized to, before the crop by alignment.
+ */
+pub fn get_resize_size(original_size: Size, url_props: &UrlProps) -> Size {
+    let new_size = Size {
+        width: url_props.width,
+        height: url_props.height,
+    };
+    calc::get_resize_size(original_size, new_size, url_props.fit_in)
+}
+
+pub fn resize(img: &ImageWithType, url_props: &UrlProps) -> Result<Mat, ThumborError> {
+    let new_aspect = get_resize_size(img.image.size()?, url_props);
 
     let mut resized_image = Mat::default();
     opencv::imgproc::resize(
@@ -40,40 +193,165 @@ pub fn resize(img: &ImageWithType, url_props: &UrlProps) -> Mat {
         0.0,
         0.0,
         opencv::imgproc::INTER_AREA
-    ).unwrap();
+    )?;
 
-    resized_image
+    Ok(resized_image)
 }
 
-pub fn crop(resized_image: &Mat, url_props: &UrlProps, original_size: opencv::core::Size) -> Mat {
-    let new_aspect = calc::get_new_size_respecting_aspect_ratio(
-        original_size,
-        Size {
-            width: url_props.width,
-            height: url_props.height,
-        }
-    );
-
+/**
+ * Returns the rectangle of the resized image that is kept, following the alignment.
+ */
+pub fn get_alignment_crop_rect(resized_size: Size, url_props: &UrlProps) -> Result<Rect, ThumborError> {
     let halign = url_props.alignment.halign.as_str();
     let x = match halign {
         "left" => 0,
-        "center" => (new_aspect.width - url_props.width) / 2,
-        "right" => new_aspect.width - url_props.width,
-        &_ => panic!("Invalid valign: {}", halign)
+        "center" => (resized_size.width - url_props.width) / 2,
+        "right" => resized_size.width - url_props.width,
+        &_ => return Err(ThumborError::BadRequest(format!("Invalid halign: {}", halign)))
     };
 
     let valigh = url_props.alignment.valign.as_str();
     let y = match valigh {
         "top" => 0,
-        "middle" => (new_aspect.height - url_props.height) / 2,
-        "bottom" => new_aspect.height - url_props.height,
-        &_ => panic!("Invalid valign: {}", valigh)
+        "middle" => (resized_size.height - url_props.height) / 2,
+        "bottom" => resized_size.height - url_props.height,
+        &_ => return Err(ThumborError::BadRequest(format!("Invalid valign: {}", valigh)))
     };
 
-    Mat::roi(resized_image, opencv::core::Rect {
+    Ok(Rect {
         x,
         y,
         width: url_props.width,
         height: url_props.height,
-    }).unwrap()
+    })
+}
+
+/**
+ * Returns the rectangle of the resized image that is kept. It is centred on the focal points, in
+ * coordinates of the original image, and follows the alignment when there are none.
+ */
+pub fn get_target_crop_rect(url_props: &UrlProps, original_size: Size, focal_points: &[FocalPoint]) -> Result<Rect, ThumborError> {
+    let target_size = Size {
+        width: url_props.width,
+        height: url_props.height,
+    };
+    let new_aspect = calc::get_new_size_respecting_aspect_ratio(original_size, target_size);
+
+    match smart::get_center(focal_points) {
+        Some(focal_point) => Ok(calc::get_focal_crop_rect(original_size, new_aspect, target_size, focal_point)),
+        None => get_alignment_crop_rect(new_aspect, url_props),
+    }
+}
+
+/**
+ * Crops the resized image to the target size, see `get_target_crop_rect`.
+ */
+pub fn crop(resized_image: &Mat, url_props: &UrlProps, original_size: opencv::core::Size, focal_points: &[FocalPoint]) -> Result<Mat, ThumborError> {
+    let rect = get_target_crop_rect(url_props, original_size, focal_points)?;
+    Ok(Mat::roi(resized_image, rect)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::CV_8UC3;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+    use crate::url_parser;
+
+    fn image_with_rects(background: Scalar, rects: &[(Rect, Scalar)]) -> Mat {
+        let mut image = Mat::new_rows_cols_with_default(80, 100, CV_8UC3, background).unwrap();
+        for (rect, color) in rects {
+            opencv::imgproc::rectangle(&mut image, *rect, *color, opencv::imgproc::FILLED, opencv::imgproc::LINE_8, 0).unwrap();
+        }
+        image
+    }
+
+    fn trim_options(corner: Option<TrimCorner>, tolerance: Option<i32>) -> Trim {
+        Trim { corner, tolerance }
+    }
+
+    #[test]
+    fn test_get_trim_rect_bordered_image() {
+        let content = Rect { x: 10, y: 20, width: 30, height: 40 };
+        let image = image_with_rects(Scalar::all(255.0), &[(content, Scalar::all(0.0))]);
+
+        assert_eq!(get_trim_rect(&image, &trim_options(None, None)).unwrap(), Some(content));
+        assert_eq!(get_trim_rect(&image, &trim_options(Some(TrimCorner::TopLeft), None)).unwrap(), Some(content));
+        assert_eq!(get_trim_rect(&image, &trim_options(Some(TrimCorner::BottomRight), None)).unwrap(), Some(content));
+    }
+
+    #[test]
+    fn test_get_trim_rect_samples_the_corner() {
+        // Only the bottom right pixel is red
+        let content = Rect { x: 10, y: 20, width: 30, height: 40 };
+        let corner = Rect { x: 99, y: 79, width: 1, height: 1 };
+        let image = image_with_rects(
+            Scalar::all(255.0),
+            &[(content, Scalar::all(0.0)), (corner, Scalar::new(0.0, 0.0, 255.0, 0.0))],
+        );
+
+        assert_eq!(
+            get_trim_rect(&image, &trim_options(Some(TrimCorner::TopLeft), None)).unwrap(),
+            Some(Rect { x: 10, y: 20, width: 90, height: 60 })
+        );
+        assert_eq!(
+            get_trim_rect(&image, &trim_options(Some(TrimCorner::BottomRight), None)).unwrap(),
+            Some(Rect { x: 0, y: 0, width: 100, height: 80 })
+        );
+    }
+
+    #[test]
+    fn test_get_trim_rect_tolerance() {
+        // The first rect is at a distance of 10 from the background colour
+        let close = Rect { x: 10, y: 10, width: 20, height: 20 };
+        let far = Rect { x: 50, y: 40, width: 10, height: 10 };
+        let image = image_with_rects(
+            Scalar::all(200.0),
+            &[(close, Scalar::new(210.0, 200.0, 200.0, 0.0)), (far, Scalar::all(0.0))],
+        );
+
+        assert_eq!(
+            get_trim_rect(&image, &trim_options(None, Some(0))).unwrap(),
+            Some(Rect { x: 10, y: 10, width: 50, height: 40 })
+        );
+        assert_eq!(
+            get_trim_rect(&image, &trim_options(None, Some(9))).unwrap(),
+            Some(Rect { x: 10, y: 10, width: 50, height: 40 })
+        );
+        assert_eq!(get_trim_rect(&image, &trim_options(None, Some(10))).unwrap(), Some(far));
+    }
+
+    #[test]
+    fn test_trim_single_colour_image() {
+        let image = image_with_rects(Scalar::all(128.0), &[]);
+
+        assert_eq!(get_trim_rect(&image, &trim_options(None, None)).unwrap(), None);
+        assert_eq!(get_trim_rect(&image, &trim_options(Some(TrimCorner::BottomRight), Some(5))).unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_source_region() {
+        let content = Rect { x: 10, y: 20, width: 30, height: 40 };
+        let image = image_with_rects(Scalar::all(255.0), &[(content, Scalar::all(0.0))]);
+
+        let region = get_source_region(&image, &url_parser::parse("trim/image.jpg").unwrap()).unwrap();
+        assert_eq!(region.image.size().unwrap(), Size { width: 30, height: 40 });
+        assert_eq!(region.origin, Point { x: 10, y: 20 });
+        assert_eq!(region.crops, vec![content]);
+
+        // The crop box is relative to the trimmed image
+        let region = get_source_region(&image, &url_parser::parse("trim/10x5:20x25/image.jpg").unwrap()).unwrap();
+        assert_eq!(region.image.size().unwrap(), Size { width: 10, height: 20 });
+        assert_eq!(region.origin, Point { x: 20, y: 25 });
+        assert_eq!(region.crops, vec![content, Rect { x: 10, y: 5, width: 10, height: 20 }]);
+
+        let outside = get_source_region(&image, &url_parser::parse("trim/40x0:50x10/image.jpg").unwrap());
+        assert!(matches!(outside, Err(ThumborError::BadRequest(_))));
+
+        // Nothing to trim on a single colour image
+        let region = get_source_region(&image_with_rects(Scalar::all(128.0), &[]), &url_parser::parse("trim/image.jpg").unwrap()).unwrap();
+        assert_eq!(region.image.size().unwrap(), Size { width: 100, height: 80 });
+        assert_eq!(region.origin, Point::default());
+        assert!(region.crops.is_empty());
+    }
 }
@@ -0,0 +1,549 @@
+use std::io::{Read, Write};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use opencv::core::Size;
+
+use crate::error::ThumborError;
+use crate::image::encoder::ImageFormat;
+
+pub const ORIENTATION_TAG: u16 = 0x0112;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const JPEG_ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+// Largest JPEG segment, minus the length, the ICC header, the sequence number and the count
+const JPEG_ICC_CHUNK_SIZE: usize = 65535 - 2 - 14;
+const JPEG_MAX_EXIF_SIZE: usize = 65535 - 2 - 6;
+// The sequence number and the count of the ICC segments are a single byte
+const JPEG_MAX_ICC_CHUNKS: usize = 255;
+
+/**
+ * EXIF and ICC profile copied from the source to the output.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ImageMetadata {
+    pub exif: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+}
+
+/**
+ * EXIF orientation values, as the rotation or mirroring that makes the image upright.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Normal,
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270,
+}
+
+impl Orientation {
+    pub fn from_exif(value: u16) -> Option<Orientation> {
+        match value {
+            1 => Some(Orientation::Normal),
+            2 => Some(Orientation::FlipHorizontal),
+            3 => Some(Orientation::Rotate180),
+            4 => Some(Orientation::FlipVertical),
+            5 => Some(Orientation::Transpose),
+            6 => Some(Orientation::Rotate90),
+            7 => Some(Orientation::Transverse),
+            8 => Some(Orientation::Rotate270),
+            _ => None,
+        }
+    }
+
+    /**
+     * Returns whether the width and height of the stored image are swapped.
+     */
+    pub fn swaps_dimensions(&self) -> bool {
+        matches!(self, Orientation::Transpose | Orientation::Rotate90 | Orientation::Transverse | Orientation::Rotate270)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize, little_endian: bool) -> Option<u16> {
+    let value: [u8; 2] = bytes.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if little_endian { u16::from_le_bytes(value) } else { u16::from_be_bytes(value) })
+}
+
+fn read_u32(bytes: &[u8], offset: usize, little_endian: bool) -> Option<u32> {
+    let value: [u8; 4] = bytes.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if little_endian { u32::from_le_bytes(value) } else { u32::from_be_bytes(value) })
+}
+
+fn strip_exif_header(data: &[u8]) -> &[u8] {
+    data.strip_prefix(EXIF_HEADER).unwrap_or(data)
+}
+
+/**
+ * Returns the marker and data of the JPEG segments before the image data.
+ */
+fn jpeg_segments(bytes: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = vec![];
+    let mut offset = 2;
+    while bytes.get(offset) == Some(&0xFF) {
+        let marker = match bytes.get(offset + 1) {
+            // Start of scan, the image data follows
+            Some(0xDA) | None => break,
+            Some(marker) => *marker,
+        };
+        let data = match read_u16(bytes, offset + 2, false).and_then(|length| bytes.get(offset + 4..offset + 2 + length as usize)) {
+            Some(data) => data,
+            None => break,
+        };
+        segments.push((marker, data));
+        offset += 4 + data.len();
+    }
+    segments
+}
+
+/**
+ * Returns the type and data of the PNG chunks before the image data.
+ */
+fn png_chunks(bytes: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut chunks = vec![];
+    let mut offset = 8;
+    while let Some(length) = read_u32(bytes, offset, false) {
+        let (chunk_type, data) = match (bytes.get(offset + 4..offset + 8), bytes.get(offset + 8..offset + 8 + length as usize)) {
+            (Some(chunk_type), Some(data)) => (chunk_type, data),
+            _ => break,
+        };
+        if chunk_type == b"IDAT" || chunk_type == b"IEND" {
+            break;
+        }
+        chunks.push((chunk_type, data));
+        offset += 12 + data.len();
+    }
+    chunks
+}
+
+/**
+ * Returns the fourcc and data of every WebP chunk.
+ */
+fn webp_chunks(bytes: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut chunks = vec![];
+    let mut offset = 12;
+    while let Some(length) = read_u32(bytes, offset + 4, true) {
+        let (fourcc, data) = match (bytes.get(offset..offset + 4), bytes.get(offset + 8..offset + 8 + length as usize)) {
+            (Some(fourcc), Some(data)) => (fourcc, data),
+            _ => break,
+        };
+        chunks.push((fourcc, data));
+        // Chunks are padded to an even size
+        offset += 8 + data.len() + (data.len() & 1);
+    }
+    chunks
+}
+
+/**
+ * Returns the TIFF structure with the EXIF data of a JPEG (APP1), PNG (eXIf) or WebP (EXIF) file.
+ */
+pub fn find_exif(bytes: &[u8]) -> Option<&[u8]> {
+    match ImageFormat::detect(bytes)? {
+        ImageFormat::Jpeg => jpeg_segments(bytes)
+            .into_iter()
+            .find(|(marker, data)| *marker == 0xE1 && data.starts_with(EXIF_HEADER))
+            .map(|(_, data)| strip_exif_header(data)),
+        ImageFormat::Png => png_chunks(bytes)
+            .into_iter()
+            .find(|(chunk_type, _)| *chunk_type == b"eXIf")
+            .map(|(_, data)| data),
+        ImageFormat::Webp => webp_chunks(bytes)
+            .into_iter()
+            .find(|(fourcc, _)| *fourcc == b"EXIF")
+            .map(|(_, data)| strip_exif_header(data)),
+        ImageFormat::Avif | ImageFormat::Gif => None,
+    }
+}
+
+/**
+ * Returns the ICC profile of a JPEG (APP2, possibly split), PNG (iCCP) or WebP (ICCP) file.
+ */
+pub fn find_icc(bytes: &[u8]) -> Option<Vec<u8>> {
+    match ImageFormat::detect(bytes)? {
+        ImageFormat::Jpeg => {
+            let mut chunks: Vec<(u8, &[u8])> = jpeg_segments(bytes)
+                .into_iter()
+                .filter(|(marker, data)| *marker == 0xE2 && data.starts_with(JPEG_ICC_HEADER) && data.len() > JPEG_ICC_HEADER.len() + 2)
+                .map(|(_, data)| (data[JPEG_ICC_HEADER.len()], &data[JPEG_ICC_HEADER.len() + 2..]))
+                .collect();
+            chunks.sort_by_key(|(sequence_number, _)| *sequence_number);
+            let icc: Vec<u8> = chunks.into_iter().flat_map(|(_, chunk)| chunk.to_vec()).collect();
+            Some(icc).filter(|icc| !icc.is_empty())
+        }
+        ImageFormat::Png => {
+            let (_, data) = png_chunks(bytes).into_iter().find(|(chunk_type, _)| *chunk_type == b"iCCP")?;
+            // Profile name, null separator and compression method before the zlib stream
+            let name_end = data.iter().position(|byte| *byte == 0)?;
+            let mut icc = vec![];
+            ZlibDecoder::new(data.get(name_end + 2..)?).read_to_end(&mut icc).ok()?;
+            Some(icc)
+        }
+        ImageFormat::Webp => webp_chunks(bytes)
+            .into_iter()
+            .find(|(fourcc, _)| *fourcc == b"ICCP")
+            .map(|(_, data)| data.to_vec()),
+        ImageFormat::Avif | ImageFormat::Gif => None,
+    }
+}
+
+/**
+ * Returns the offset of the first IFD entry with the tag, and whether the TIFF is little endian.
+ */
+fn find_tag_entry(tiff: &[u8], tag: u16) -> Option<(usize, bool)> {
+    let little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    if read_u16(tiff, 2, little_endian)? != 42 {
+        return None;
+    }
+
+    let ifd_offset = read_u32(tiff, 4, little_endian)? as usize;
+    let entries = read_u16(tiff, ifd_offset, little_endian)? as usize;
+    (0..entries)
+        .map(|index| ifd_offset + 2 + index * 12)
+        .find(|entry| read_u16(tiff, *entry, little_endian) == Some(tag))
+        .map(|entry| (entry, little_endian))
+}
+
+/**
+ * Returns the value of a SHORT tag of the first IFD of the TIFF structure.
+ */
+pub fn read_tag(tiff: &[u8], tag: u16) -> Option<u16> {
+    let (entry, little_endian) = find_tag_entry(tiff, tag)?;
+    read_u16(tiff, entry + 8, little_endian)
+}
+
+/**
+ * Overwrites the value of a SHORT tag of the first IFD. Returns whether the tag was found.
+ */
+pub fn write_tag(tiff: &mut [u8], tag: u16, value: u16) -> bool {
+    let (entry, little_endian) = match find_tag_entry(tiff, tag) {
+        Some(found) => found,
+        None => return false,
+    };
+    let value = if little_endian { value.to_le_bytes() } else { value.to_be_bytes() };
+    match tiff.get_mut(entry + 8..entry + 10) {
+        Some(bytes) => {
+            bytes.copy_from_slice(&value);
+            true
+        }
+        None => false,
+    }
+}
+
+/**
+ * Returns the EXIF orientation of the file, `None` when it has no EXIF or an invalid value.
+ */
+pub fn read_orientation(bytes: &[u8]) -> Option<Orientation> {
+    find_exif(bytes)
+        .and_then(|tiff| read_tag(tiff, ORIENTATION_TAG))
+        .and_then(Orientation::from_exif)
+}
+
+/**
+ * Reads the EXIF and ICC profile of the file. The orientation is reset, the decoded image is already upright.
+ */
+pub fn read_metadata(bytes: &[u8]) -> ImageMetadata {
+    let exif = find_exif(bytes).map(|tiff| {
+        let mut tiff = tiff.to_vec();
+        write_tag(&mut tiff, ORIENTATION_TAG, 1);
+        tiff
+    });
+    ImageMetadata { exif, icc: find_icc(bytes) }
+}
+
+fn jpeg_segment(marker: u8, data: &[&[u8]]) -> Vec<u8> {
+    let length: usize = data.iter().map(|part| part.len()).sum();
+    let mut segment = vec![0xFF, marker];
+    segment.extend((length as u16 + 2).to_be_bytes());
+    data.iter().for_each(|part| segment.extend(*part));
+    segment
+}
+
+fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(chunk_type);
+    crc.update(data);
+
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend(chunk_type);
+    chunk.extend(data);
+    chunk.extend(crc.finalize().to_be_bytes());
+    chunk
+}
+
+fn webp_chunk(fourcc: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut chunk = fourcc.to_vec();
+    chunk.extend((data.len() as u32).to_le_bytes());
+    chunk.extend(data);
+    if data.len() & 1 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+/**
+ * Fails with `Internal` when the EXIF does not fit in one segment, or the ICC profile in 255.
+ */
+fn embed_jpeg(bytes: Vec<u8>, metadata: &ImageMetadata) -> Result<Vec<u8>, ThumborError> {
+    // After the JFIF segment, which must be the first one
+    let mut offset = 2;
+    if let Some((0xE0, data)) = jpeg_segments(&bytes).first() {
+        offset += 4 + data.len();
+    }
+
+    let mut segments = vec![];
+    if let Some(exif) = &metadata.exif {
+        if exif.len() > JPEG_MAX_EXIF_SIZE {
+            return Err(ThumborError::Internal(format!("EXIF of {} bytes does not fit in a JPEG", exif.len())));
+        }
+        segments.extend(jpeg_segment(0xE1, &[EXIF_HEADER, exif]));
+    }
+    if let Some(icc) = &metadata.icc {
+        let count = icc.chunks(JPEG_ICC_CHUNK_SIZE).count();
+        if count > JPEG_MAX_ICC_CHUNKS {
+            return Err(ThumborError::Internal(format!("ICC profile of {} bytes does not fit in a JPEG", icc.len())));
+        }
+        for (index, chunk) in icc.chunks(JPEG_ICC_CHUNK_SIZE).enumerate() {
+            segments.extend(jpeg_segment(0xE2, &[JPEG_ICC_HEADER, &[index as u8 + 1, count as u8], chunk]));
+        }
+    }
+
+    let mut embedded = bytes[..offset].to_vec();
+    embedded.extend(segments);
+    embedded.extend(&bytes[offset..]);
+    Ok(embedded)
+}
+
+fn embed_png(bytes: Vec<u8>, metadata: &ImageMetadata) -> Vec<u8> {
+    // After the IHDR chunk, which must be the first one
+    let offset = 8 + 12 + 13;
+
+    let mut chunks = vec![];
+    if let Some(icc) = &metadata.icc {
+        let mut encoder = ZlibEncoder::new(b"ICC Profile\0\0".to_vec(), Compression::default());
+        if let Ok(data) = encoder.write_all(icc).and_then(|_| encoder.finish()) {
+            chunks.extend(png_chunk(b"iCCP", &data));
+        }
+    }
+    if let Some(exif) = &metadata.exif {
+        chunks.extend(png_chunk(b"eXIf", exif));
+    }
+
+    let mut embedded = bytes[..offset].to_vec();
+    embedded.extend(chunks);
+    embedded.extend(&bytes[offset..]);
+    embedded
+}
+
+fn embed_webp(bytes: Vec<u8>, metadata: &ImageMetadata, size: Size) -> Vec<u8> {
+    let chunks = webp_chunks(&bytes);
+
+    // Simple files (a single VP8 or VP8L chunk) must be turned into the extended format
+    let mut vp8x = match chunks.first() {
+        Some((b"VP8X", data)) => data.to_vec(),
+        _ => {
+            let mut vp8x = vec![0; 4];
+            vp8x.extend(&((size.width - 1) as u32).to_le_bytes()[..3]);
+            vp8x.extend(&((size.height - 1) as u32).to_le_bytes()[..3]);
+            vp8x
+        }
+    };
+    if let Some((b"VP8L", data)) = chunks.first() {
+        if data.get(4).map(|header| header & 0x10 != 0).unwrap_or(false) {
+            vp8x[0] |= 0x10;
+        }
+    }
+    if metadata.icc.is_some() {
+        vp8x[0] |= 0x20;
+    }
+    if metadata.exif.is_some() {
+        vp8x[0] |= 0x08;
+    }
+
+    let mut body = b"WEBP".to_vec();
+    body.extend(webp_chunk(b"VP8X", &vp8x));
+    if let Some(icc) = &metadata.icc {
+        body.extend(webp_chunk(b"ICCP", icc));
+    }
+    for (fourcc, data) in chunks.iter().filter(|(fourcc, _)| !matches!(*fourcc, b"VP8X" | b"ICCP" | b"EXIF")) {
+        body.extend(webp_chunk(fourcc, data));
+    }
+    if let Some(exif) = &metadata.exif {
+        body.extend(webp_chunk(b"EXIF", exif));
+    }
+
+    let mut embedded = b"RIFF".to_vec();
+    embedded.extend((body.len() as u32).to_le_bytes());
+    embedded.extend(body);
+    embedded
+}
+
+/**
+ * Adds the EXIF and ICC profile to the encoded JPEG, PNG or WebP file. Other formats are returned as they are.
+ */
+pub fn embed(bytes: Vec<u8>, format: ImageFormat, metadata: &ImageMetadata, size: Size) -> Result<Vec<u8>, ThumborError> {
+    if metadata.exif.is_none() && metadata.icc.is_none() {
+        return Ok(bytes);
+    }
+
+    match format {
+        ImageFormat::Jpeg => embed_jpeg(bytes, metadata),
+        ImageFormat::Png => Ok(embed_png(bytes, metadata)),
+        ImageFormat::Webp => Ok(embed_webp(bytes, metadata, size)),
+        ImageFormat::Avif | ImageFormat::Gif => Ok(bytes),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    /**
+     * Returns a little endian TIFF structure with only the orientation tag.
+     */
+    pub fn orientation_tiff(orientation: u16) -> Vec<u8> {
+        let mut tiff = b"II\x2a\x00\x08\x00\x00\x00\x01\x00".to_vec();
+        tiff.extend(ORIENTATION_TAG.to_le_bytes());
+        tiff.extend([3, 0, 1, 0, 0, 0]);
+        tiff.extend(orientation.to_le_bytes());
+        tiff.extend([0, 0, 0, 0, 0, 0]);
+        tiff
+    }
+
+    /**
+     * Inserts an APP1 segment with the EXIF orientation right after the start of the JPEG.
+     */
+    pub fn jpeg_with_orientation(jpeg: &[u8], orientation: u16) -> Vec<u8> {
+        let mut segment = b"Exif\0\0".to_vec();
+        segment.extend(orientation_tiff(orientation));
+
+        let mut bytes = jpeg[..2].to_vec();
+        bytes.extend([0xFF, 0xE1]);
+        bytes.extend((segment.len() as u16 + 2).to_be_bytes());
+        bytes.extend(segment);
+        bytes.extend(&jpeg[2..]);
+        bytes
+    }
+
+    #[test]
+    fn test_read_tag() {
+        assert_eq!(read_tag(&orientation_tiff(6), ORIENTATION_TAG), Some(6));
+        assert_eq!(read_tag(&orientation_tiff(6), 0x0100), None);
+        assert_eq!(read_tag(b"MM\x00\x2a\x00\x00\x00\x08\x00\x01\x01\x12\x00\x03\x00\x00\x00\x01\x00\x08\x00\x00", ORIENTATION_TAG), Some(8));
+        assert_eq!(read_tag(b"not a tiff", ORIENTATION_TAG), None);
+    }
+
+    #[test]
+    fn test_read_orientation_from_jpeg() {
+        let jpeg = std::fs::read("./src/images/sun.jpg").unwrap();
+        assert_eq!(read_orientation(&jpeg_with_orientation(&jpeg, 6)), Some(Orientation::Rotate90));
+        assert_eq!(read_orientation(&jpeg_with_orientation(&jpeg, 9)), None);
+    }
+
+    #[test]
+    fn test_read_orientation_from_png_and_webp() {
+        let tiff = orientation_tiff(3);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend((tiff.len() as u32).to_be_bytes());
+        png.extend(b"eXIf");
+        png.extend(&tiff);
+        png.extend([0, 0, 0, 0]);
+        assert_eq!(read_orientation(&png), Some(Orientation::Rotate180));
+
+        let mut webp = b"RIFF\x00\x00\x00\x00WEBPEXIF".to_vec();
+        webp.extend((tiff.len() as u32).to_le_bytes());
+        webp.extend(&tiff);
+        assert_eq!(read_orientation(&webp), Some(Orientation::Rotate180));
+    }
+
+    #[test]
+    fn test_write_tag() {
+        let mut tiff = orientation_tiff(6);
+        assert!(write_tag(&mut tiff, ORIENTATION_TAG, 1));
+        assert_eq!(read_tag(&tiff, ORIENTATION_TAG), Some(1));
+        assert!(!write_tag(&mut tiff, 0x0100, 1));
+    }
+
+    #[test]
+    fn test_read_metadata_resets_orientation() {
+        let jpeg = std::fs::read("./src/images/sun.jpg").unwrap();
+        let metadata = read_metadata(&jpeg_with_orientation(&jpeg, 6));
+        assert_eq!(metadata.exif, Some(orientation_tiff(1)));
+    }
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let image = opencv::core::Mat::new_rows_cols_with_default(10, 20, opencv::core::CV_8UC3, opencv::core::Scalar::all(128.0)).unwrap();
+        let mut bytes: opencv::core::Vector<u8> = opencv::core::Vector::new();
+        opencv::imgcodecs::imencode(format.extension(), &image, &mut bytes, &opencv::core::Vector::new()).unwrap();
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn test_embed_round_trip() {
+        // Big enough to be split into several JPEG segments
+        let icc: Vec<u8> = (0..150_000).map(|index| (index % 251) as u8).collect();
+        let metadata = ImageMetadata { exif: Some(orientation_tiff(1)), icc: Some(icc) };
+
+        for format in [ImageFormat::Jpeg, ImageFormat::Png] {
+            let embedded = embed(encode(format), format, &metadata, Size::new(20, 10)).unwrap();
+            assert_eq!(read_metadata(&embedded), metadata);
+        }
+
+        let embedded = embed(encode(ImageFormat::Jpeg), ImageFormat::Jpeg, &metadata, Size::new(20, 10)).unwrap();
+        let decoded = opencv::imgcodecs::imdecode(&opencv::core::Mat::from_slice(&embedded).unwrap(), opencv::imgcodecs::IMREAD_COLOR).unwrap();
+        assert_eq!(opencv::prelude::MatTraitConstManual::size(&decoded).unwrap(), Size::new(20, 10));
+    }
+
+    #[test]
+    fn test_embed_webp_uses_extended_format() {
+        // Lossless 20x10 header with alpha, the image data does not matter here
+        let mut webp = b"RIFF\x00\x00\x00\x00WEBP".to_vec();
+        webp.extend(webp_chunk(b"VP8L", &[0x2f, 0x13, 0x40, 0x02, 0x10, 0x00]));
+        let metadata = ImageMetadata { exif: Some(orientation_tiff(1)), icc: Some(b"profile".to_vec()) };
+
+        let embedded = embed(webp, ImageFormat::Webp, &metadata, Size::new(20, 10)).unwrap();
+        let chunks = webp_chunks(&embedded);
+        let fourccs: Vec<&[u8]> = chunks.iter().map(|(fourcc, _)| *fourcc).collect();
+        assert_eq!(fourccs, vec![&b"VP8X"[..], b"ICCP", b"VP8L", b"EXIF"]);
+        assert_eq!(chunks[0].1, &[0x38, 0, 0, 0, 19, 0, 0, 9, 0, 0][..]);
+        assert_eq!(u32::from_le_bytes(embedded[4..8].try_into().unwrap()) as usize, embedded.len() - 8);
+        assert_eq!(read_metadata(&embedded), metadata);
+    }
+
+    #[test]
+    fn test_embed_without_metadata() {
+        let jpeg = encode(ImageFormat::Jpeg);
+        assert_eq!(embed(jpeg.clone(), ImageFormat::Jpeg, &ImageMetadata::default(), Size::new(20, 10)), Ok(jpeg));
+    }
+
+    #[test]
+    fn test_embed_jpeg_too_large_metadata() {
+        let jpeg = encode(ImageFormat::Jpeg);
+        let exif = ImageMetadata { exif: Some(vec![0; JPEG_MAX_EXIF_SIZE + 1]), icc: None };
+        assert!(matches!(embed(jpeg.clone(), ImageFormat::Jpeg, &exif, Size::new(20, 10)), Err(ThumborError::Internal(_))));
+
+        // 256 segments, the count would wrap to 0
+        let icc = ImageMetadata { exif: None, icc: Some(vec![0; JPEG_ICC_CHUNK_SIZE * JPEG_MAX_ICC_CHUNKS + 1]) };
+        assert!(matches!(embed(jpeg.clone(), ImageFormat::Jpeg, &icc, Size::new(20, 10)), Err(ThumborError::Internal(_))));
+
+        let icc = ImageMetadata { exif: None, icc: Some(vec![0; JPEG_ICC_CHUNK_SIZE * JPEG_MAX_ICC_CHUNKS]) };
+        let embedded = embed(jpeg, ImageFormat::Jpeg, &icc, Size::new(20, 10)).unwrap();
+        assert_eq!(read_metadata(&embedded), icc);
+    }
+
+    #[test]
+    fn test_swaps_dimensions() {
+        assert!(Orientation::Rotate90.swaps_dimensions());
+        assert!(Orientation::Transverse.swaps_dimensions());
+        assert!(!Orientation::Rotate180.swaps_dimensions());
+        assert!(!Orientation::FlipHorizontal.swaps_dimensions());
+    }
+}
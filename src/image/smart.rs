@@ -0,0 +1,384 @@
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+use opencv::core::{Mat, Point, Point2f, Rect, Scalar, Size};
+use opencv::prelude::MatTraitConst;
+use opencv::prelude::MatTraitConstManual; // to get method `.size()` must have this use
+use serde::{Deserialize, Serialize};
+
+use crate::error::ThumborError;
+use crate::registry::Registry;
+use crate::settings::{conf, Settings};
+use crate::url_parser;
+use crate::url_props::UrlProps;
+
+pub mod cache;
+pub mod detectors;
+
+lazy_static! {
+    static ref REGISTRY: RwLock<DetectorRegistry> = RwLock::new(DetectorRegistry::with_builtin_detectors());
+}
+
+// Detection runs on a copy no bigger than this, it is slow on big images
+pub const DETECTION_MAX_SIDE: i32 = 600;
+
+/**
+ * Interesting region of the image, in coordinates of the image the detection ran on.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FocalPoint {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub weight: f32,
+}
+
+impl FocalPoint {
+    pub fn from_rect(rect: Rect, weight: f32) -> Self {
+        FocalPoint { x: rect.x, y: rect.y, width: rect.width, height: rect.height, weight }
+    }
+
+    pub fn center(&self) -> Point2f {
+        Point2f {
+            x: self.x as f32 + self.width as f32 / 2.0,
+            y: self.y as f32 + self.height as f32 / 2.0,
+        }
+    }
+}
+
+/**
+ * Returns the weighted center of the focal points, `None` when there are none.
+ */
+pub fn get_center(focal_points: &[FocalPoint]) -> Option<Point2f> {
+    let total_weight: f32 = focal_points.iter().map(|focal_point| focal_point.weight).sum();
+    if focal_points.is_empty() || total_weight <= 0.0 {
+        return None;
+    }
+
+    let (x, y) = focal_points.iter().fold((0.0, 0.0), |(x, y), focal_point| {
+        let center = focal_point.center();
+        (x + center.x * focal_point.weight, y + center.y * focal_point.weight)
+    });
+    Some(Point2f { x: x / total_weight, y: y / total_weight })
+}
+
+/**
+ * Returns the rectangles of the `focal()` filters as focal points weighted by their area.
+ * They are in source coordinates, `origin` is where the image being cropped starts in the source.
+ */
+pub fn get_url_focal_points(url_props: &UrlProps, origin: Point) -> Vec<FocalPoint> {
+    url_props
+        .filters
+        .iter()
+        .filter(|filter| filter.name == "focal")
+        .filter_map(|filter| filter.args.first().and_then(|arg| url_parser::parse_focal_rect(arg)))
+        .map(|crop_box| {
+            let rect = Rect {
+                x: crop_box.left - origin.x,
+                y: crop_box.top - origin.y,
+                width: crop_box.right - crop_box.left,
+                height: crop_box.bottom - crop_box.top,
+            };
+            FocalPoint::from_rect(rect, rect.area() as f32)
+        })
+        .collect()
+}
+
+/**
+ * Returns whether the crop runs the detectors: with `smart`, when the image is cropped and the
+ * url has no `focal()` rectangle.
+ */
+pub fn needs_detection(url_props: &UrlProps) -> bool {
+    url_props.alignment.smart && url_props.fit_in.is_none() && get_url_focal_points(url_props, Point::default()).is_empty()
+}
+
+/**
+ * Returns the focal points the crop is centred on: the `focal()` rectangles, or with `smart` the
+ * focal points of the first detector that finds something. `image` is the source after trim and
+ * manual crop, starting at `origin`. The detection is cached per source when `source_digest` is given.
+ * Fit-in does not crop, so it has none.
+ */
+pub fn get_focal_points(
+    image: &Mat,
+    url_props: &UrlProps,
+    origin: Point,
+    detector_registry: &DetectorRegistry,
+    source_digest: Option<&str>,
+) -> Result<Vec<FocalPoint>, ThumborError> {
+    if url_props.fit_in.is_some() {
+        return Ok(vec![]);
+    }
+    if !needs_detection(url_props) {
+        return Ok(get_url_focal_points(url_props, origin));
+    }
+
+    let detectors = get_detector_names(url_props);
+    let detect = || detector_registry.detect(image, &detectors);
+    match source_digest {
+        Some(digest) => {
+            let size = image.size()?;
+            let region = Rect { x: origin.x, y: origin.y, width: size.width, height: size.height };
+            cache::detection_cache().get_or_detect(&cache::get_key(digest, &detectors, region), detect)
+        }
+        None => detect(),
+    }
+}
+
+/**
+ * Returns a grayscale copy of the image no bigger than `max_side`, and the factor to scale
+ * its coordinates back to the image.
+ */
+pub fn get_detection_image(image: &Mat, max_side: i32) -> Result<(Mat, f32), ThumborError> {
+    let conversion = match image.channels() {
+        4 => opencv::imgproc::COLOR_BGRA2GRAY,
+        _ => opencv::imgproc::COLOR_BGR2GRAY,
+    };
+    let mut gray_image = Mat::default();
+    opencv::imgproc::cvt_color(image, &mut gray_image, conversion, 0)?;
+
+    let size = gray_image.size()?;
+    let scale = (max_side as f32 / size.width.max(size.height) as f32).min(1.0);
+    if scale >= 1.0 {
+        return Ok((gray_image, 1.0));
+    }
+
+    let mut small_image = Mat::default();
+    let small_size = Size {
+        width: ((size.width as f32 * scale).round() as i32).max(1),
+        height: ((size.height as f32 * scale).round() as i32).max(1),
+    };
+    opencv::imgproc::resize(&gray_image, &mut small_image, small_size, 0.0, 0.0, opencv::imgproc::INTER_AREA)?;
+    Ok((small_image, 1.0 / scale))
+}
+
+/**
+ * Finds the interesting regions of an image for smart crops.
+ */
+pub trait Detector: Send + Sync {
+    /**
+     * Returns the focal points in coordinates of the image, empty when nothing was found.
+     */
+    fn detect(&self, image: &Mat) -> Result<Vec<FocalPoint>, ThumborError>;
+}
+
+pub type DetectorRegistry = Registry<dyn Detector>;
+
+impl Registry<dyn Detector> {
+    pub fn with_builtin_detectors() -> Self {
+        let mut registry = DetectorRegistry::new();
+        detectors::register_builtin_detectors(&mut registry);
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, detector: impl Detector + 'static) {
+        self.insert(name, Arc::new(detector));
+    }
+
+    fn find_unknown<'a>(&self, names: &'a [String]) -> Option<&'a String> {
+        names.iter().find(|name| !self.contains(name))
+    }
+
+    /**
+     * Checks that every detector requested by the url exists.
+     */
+    pub fn validate(&self, names: &[String]) -> Result<(), ThumborError> {
+        match self.find_unknown(names) {
+            Some(name) => Err(ThumborError::BadRequest(format!("Unknown detector: {}", name))),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Checks the `smart_detectors` setting and the cascades of the detectors. It is a server error,
+     * so it runs once at startup instead of failing every smart request.
+     */
+    pub fn validate_settings(&self, settings: &Settings) -> Result<(), ThumborError> {
+        if let Some(name) = self.find_unknown(&settings.smart_detectors) {
+            return Err(ThumborError::Internal(format!("Unknown detector in smart_detectors: {}", name)));
+        }
+        detectors::validate_cascades(settings)
+    }
+
+    /**
+     * Runs the detectors in order, and returns the focal points of the first one that finds something.
+     */
+    pub fn detect(&self, image: &Mat, names: &[String]) -> Result<Vec<FocalPoint>, ThumborError> {
+        for name in names {
+            let detector = self
+                .get(name)
+                .ok_or_else(|| ThumborError::Internal(format!("Unknown detector: {}", name)))?;
+
+            let focal_points = detector.detect(image)?;
+            if !focal_points.is_empty() {
+                return Ok(focal_points);
+            }
+        }
+        Ok(vec![])
+    }
+}
+
+/**
+ * Registers a detector in the global registry used by the server, so it can be listed in the
+ * `smart_detectors` setting or the `detectors()` filter.
+ * Call it before starting the server to add detectors from another crate.
+ */
+pub fn register(name: &str, detector: impl Detector + 'static) {
+    REGISTRY.write().unwrap().register(name, detector);
+}
+
+pub fn registry() -> DetectorRegistry {
+    REGISTRY.read().unwrap().clone()
+}
+
+/**
+ * Returns the detectors of the `detectors()` filter, or the `smart_detectors` setting without it.
+ */
+pub fn get_detector_names(url_props: &UrlProps) -> Vec<String> {
+    match url_props.filters.iter().find(|filter| filter.name == "detectors") {
+        Some(filter) => filter.args.iter().map(|arg| arg.trim().to_string()).collect(),
+        None => conf().smart_detectors.clone(),
+    }
+}
+
+/**
+ * Draws the focal points on a copy of the image, for the `debug` url flag.
+ */
+pub fn draw_focal_points(image: &Mat, focal_points: &[FocalPoint]) -> Result<Mat, ThumborError> {
+    let mut debug_image = image.clone();
+    let size = image.size()?;
+    let thickness = (size.width.max(size.height) / 300).max(2);
+    let color = Scalar::new(0.0, 0.0, 255.0, 255.0);
+
+    for focal_point in focal_points {
+        let rect = Rect { x: focal_point.x, y: focal_point.y, width: focal_point.width, height: focal_point.height };
+        opencv::imgproc::rectangle(&mut debug_image, rect, color, thickness, opencv::imgproc::LINE_8, 0)?;
+    }
+    Ok(debug_image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+    use opencv::core::CV_8UC3;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_get_center() {
+        assert_eq!(get_center(&[]), None);
+
+        let focal_points = [
+            FocalPoint::from_rect(Rect { x: 0, y: 0, width: 10, height: 10 }, 1.0),
+            FocalPoint::from_rect(Rect { x: 90, y: 0, width: 10, height: 10 }, 3.0),
+        ];
+        assert_eq!(get_center(&focal_points), Some(Point2f { x: 72.5, y: 5.0 }));
+    }
+
+    #[test]
+    fn test_needs_detection() {
+        assert!(needs_detection(&url_parser::parse("300x200/smart/big.jpg").unwrap()));
+        assert!(!needs_detection(&url_parser::parse("300x200/big.jpg").unwrap()));
+        assert!(!needs_detection(&url_parser::parse("fit-in/300x200/smart/big.jpg").unwrap()));
+        assert!(!needs_detection(&url_parser::parse("300x200/smart/filters:focal(10x10:20x20)/big.jpg").unwrap()));
+    }
+
+    #[test]
+    fn test_get_url_focal_points() {
+        let url_props = url_parser::parse("300x200/filters:focal(100x100:200x300):focal(10x10:20x20)/big.jpg").unwrap();
+        assert_eq!(get_url_focal_points(&url_props, Point::default()), vec![
+            FocalPoint { x: 100, y: 100, width: 100, height: 200, weight: 20000.0 },
+            FocalPoint { x: 10, y: 10, width: 10, height: 10, weight: 100.0 },
+        ]);
+
+        let url_props = url_parser::parse("300x200/filters:focal(100x100:200x300)/big.jpg").unwrap();
+        assert_eq!(get_url_focal_points(&url_props, Point { x: 50, y: 20 }), vec![
+            FocalPoint { x: 50, y: 80, width: 100, height: 200, weight: 20000.0 },
+        ]);
+
+        let url_props = url_parser::parse("300x200/smart/big.jpg").unwrap();
+        assert!(get_url_focal_points(&url_props, Point::default()).is_empty());
+    }
+
+    #[test]
+    fn test_get_detection_image() {
+        let image = opencv::imgcodecs::imread("./src/images/big.jpg", opencv::imgcodecs::IMREAD_COLOR).unwrap();
+        let (detection_image, scale) = get_detection_image(&image, DETECTION_MAX_SIDE).unwrap();
+        assert_eq!(detection_image.channels(), 1);
+        assert_eq!(detection_image.size().unwrap().width, DETECTION_MAX_SIDE);
+        assert!((scale - 5184.0 / DETECTION_MAX_SIDE as f32).abs() < 0.001);
+    }
+
+    struct FixedDetector(Vec<FocalPoint>);
+
+    impl Detector for FixedDetector {
+        fn detect(&self, _image: &Mat) -> Result<Vec<FocalPoint>, ThumborError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_registry_detect_in_order() {
+        let focal_point = FocalPoint { x: 1, y: 2, width: 3, height: 4, weight: 1.0 };
+        let mut registry = DetectorRegistry::new();
+        registry.register("nothing", FixedDetector(vec![]));
+        registry.register("custom", FixedDetector(vec![focal_point.clone()]));
+        registry.register("never", FixedDetector(vec![FocalPoint { x: 9, y: 9, width: 9, height: 9, weight: 9.0 }]));
+        let image = Mat::default();
+
+        assert_eq!(registry.detect(&image, &names(&["nothing", "custom", "never"])).unwrap(), vec![focal_point]);
+        assert!(registry.detect(&image, &names(&["nothing"])).unwrap().is_empty());
+        assert!(registry.detect(&image, &[]).unwrap().is_empty());
+        assert!(matches!(registry.detect(&image, &names(&["unknown"])), Err(ThumborError::Internal(_))));
+    }
+
+    #[test]
+    fn test_registry_validate() {
+        let registry = DetectorRegistry::with_builtin_detectors();
+        assert_eq!(registry.validate(&names(&["face", "feature", "profile", "eyes"])), Ok(()));
+        assert_eq!(
+            registry.validate(&names(&["face", "unknown"])),
+            Err(ThumborError::BadRequest("Unknown detector: unknown".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_registry_validate_settings() {
+        let registry = DetectorRegistry::with_builtin_detectors();
+        let settings = |detectors: &[&str]| Settings { smart_detectors: names(detectors), ..Default::default() };
+        assert_eq!(registry.validate_settings(&settings(&["face", "feature"])), Ok(()));
+        assert_eq!(
+            registry.validate_settings(&settings(&["face", "feture"])),
+            Err(ThumborError::Internal("Unknown detector in smart_detectors: feture".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_get_detector_names() {
+        Settings {
+            smart_detectors: names(&["face", "feature"]),
+            ..Default::default()
+        }.make_current();
+
+        let url_props = url_parser::parse("300x200/smart/big.jpg").unwrap();
+        assert_eq!(get_detector_names(&url_props), names(&["face", "feature"]));
+
+        let url_props = url_parser::parse("300x200/smart/filters:detectors(feature, eyes)/big.jpg").unwrap();
+        assert_eq!(get_detector_names(&url_props), names(&["feature", "eyes"]));
+    }
+
+    #[test]
+    fn test_draw_focal_points() {
+        let image = Mat::new_rows_cols_with_default(100, 100, CV_8UC3, Scalar::all(0.0)).unwrap();
+        let focal_points = [FocalPoint { x: 10, y: 10, width: 50, height: 50, weight: 1.0 }];
+
+        let debug_image = draw_focal_points(&image, &focal_points).unwrap();
+        assert_eq!(*debug_image.at_2d::<opencv::core::Vec3b>(10, 30).unwrap(), opencv::core::Vec3b::from([0, 0, 255]));
+        assert_eq!(*debug_image.at_2d::<opencv::core::Vec3b>(30, 30).unwrap(), opencv::core::Vec3b::from([0, 0, 0]));
+        assert_eq!(*image.at_2d::<opencv::core::Vec3b>(10, 30).unwrap(), opencv::core::Vec3b::from([0, 0, 0]));
+    }
+}
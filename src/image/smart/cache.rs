@@ -0,0 +1,195 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use opencv::core::Rect;
+use sha1::{Digest, Sha1};
+
+use super::FocalPoint;
+use crate::error::ThumborError;
+use crate::settings::CONF_S;
+
+lazy_static! {
+    static ref DETECTION_CACHE: DetectionCache = DetectionCache::new(
+        CONF_S.detection_cache_size,
+        Some(CONF_S.detection_cache_path.as_str()).filter(|path| !path.is_empty()).map(PathBuf::from),
+    );
+}
+
+/**
+ * Returns the hex SHA-1 of the source bytes, which identifies the image whatever its url.
+ */
+pub fn get_digest(bytes: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(bytes))
+}
+
+/**
+ * Returns the cache key of a detection. The focal points depend on the part of the source left
+ * after trim and crop, and on the detectors, but not on the size of the thumbnail.
+ */
+pub fn get_key(digest: &str, detectors: &[String], region: Rect) -> String {
+    format!(
+        "{}:{},{},{},{}:{}",
+        digest, region.x, region.y, region.width, region.height, detectors.join(",")
+    )
+}
+
+#[derive(Default)]
+struct MemoryCache {
+    entries: HashMap<String, Vec<FocalPoint>>,
+    // Insertion order, the oldest entry is evicted first
+    order: VecDeque<String>,
+}
+
+/**
+ * Focal points found by the detectors, kept in memory and optionally in a directory, so other
+ * sizes of the same source skip the detection.
+ */
+pub struct DetectionCache {
+    capacity: usize,
+    disk_path: Option<PathBuf>,
+    memory: Mutex<MemoryCache>,
+}
+
+impl DetectionCache {
+    /**
+     * A `capacity` of 0 disables the memory cache. Without `disk_path`, nothing is written to disk.
+     */
+    pub fn new(capacity: usize, disk_path: Option<PathBuf>) -> Self {
+        DetectionCache { capacity, disk_path, memory: Mutex::new(MemoryCache::default()) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0 || self.disk_path.is_some()
+    }
+
+    fn disk_file(&self, key: &str) -> Option<PathBuf> {
+        // The key has characters that are not valid in file names
+        self.disk_path.as_ref().map(|path| path.join(format!("{}.json", get_digest(key.as_bytes()))))
+    }
+
+    fn set_memory(&self, key: &str, focal_points: &[FocalPoint]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut memory = self.memory.lock().unwrap();
+        if memory.entries.insert(key.to_string(), focal_points.to_vec()).is_none() {
+            memory.order.push_back(key.to_string());
+        }
+        while memory.order.len() > self.capacity {
+            if let Some(oldest) = memory.order.pop_front() {
+                memory.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<FocalPoint>> {
+        if let Some(focal_points) = self.memory.lock().unwrap().entries.get(key) {
+            return Some(focal_points.clone());
+        }
+
+        let bytes = std::fs::read(self.disk_file(key)?).ok()?;
+        let focal_points: Vec<FocalPoint> = serde_json::from_slice(&bytes).ok()?;
+        self.set_memory(key, &focal_points);
+        Some(focal_points)
+    }
+
+    /**
+     * Stores the focal points. The cache is best effort, so disk errors are ignored.
+     */
+    pub fn set(&self, key: &str, focal_points: &[FocalPoint]) {
+        self.set_memory(key, focal_points);
+
+        if let (Some(path), Some(file)) = (&self.disk_path, self.disk_file(key)) {
+            if let Ok(json) = serde_json::to_vec(focal_points) {
+                let _ = std::fs::create_dir_all(path).and_then(|_| std::fs::write(file, json));
+            }
+        }
+    }
+
+    /**
+     * Returns the cached focal points, or runs the detection and caches its result, even when empty.
+     */
+    pub fn get_or_detect(
+        &self,
+        key: &str,
+        detect: impl FnOnce() -> Result<Vec<FocalPoint>, ThumborError>,
+    ) -> Result<Vec<FocalPoint>, ThumborError> {
+        if let Some(focal_points) = self.get(key) {
+            return Ok(focal_points);
+        }
+
+        let focal_points = detect()?;
+        self.set(key, &focal_points);
+        Ok(focal_points)
+    }
+}
+
+pub fn detection_cache() -> &'static DetectionCache {
+    &DETECTION_CACHE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    fn focal_points(x: i32) -> Vec<FocalPoint> {
+        vec![FocalPoint { x, y: 2, width: 3, height: 4, weight: 12.0 }]
+    }
+
+    #[test]
+    fn test_get_key() {
+        let detectors = vec!["face".to_string(), "feature".to_string()];
+        let region = Rect { x: 0, y: 10, width: 300, height: 200 };
+        assert_eq!(get_key("abc", &detectors, region), "abc:0,10,300,200:face,feature");
+        assert_eq!(get_digest(b"image").len(), 40);
+        assert_ne!(get_digest(b"image"), get_digest(b"other image"));
+    }
+
+    #[test]
+    fn test_memory_cache_evicts_the_oldest() {
+        let cache = DetectionCache::new(2, None);
+        cache.set("a", &focal_points(1));
+        cache.set("b", &focal_points(2));
+        cache.set("c", &focal_points(3));
+
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(focal_points(2)));
+        assert_eq!(cache.get("c"), Some(focal_points(3)));
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        let path = std::env::temp_dir().join(format!("thumbor-detection-cache-{}", std::process::id()));
+        DetectionCache::new(0, Some(path.clone())).set("a", &focal_points(1));
+
+        // Another process, or a restarted one, reads what was stored
+        let cache = DetectionCache::new(10, Some(path.clone()));
+        assert_eq!(cache.get("a"), Some(focal_points(1)));
+        assert_eq!(cache.get("b"), None);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_get_or_detect_runs_the_detection_once() {
+        let cache = DetectionCache::new(10, None);
+        let mut runs = 0;
+
+        for _ in 0..3 {
+            let result = cache.get_or_detect("a", || {
+                runs += 1;
+                Ok(vec![])
+            });
+            assert!(result.unwrap().is_empty());
+        }
+        assert_eq!(runs, 1);
+
+        let error = cache.get_or_detect("b", || Err(ThumborError::Internal("failed".to_string())));
+        assert!(error.is_err());
+        assert_eq!(cache.get("b"), None);
+    }
+}
@@ -0,0 +1,240 @@
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use opencv::core::{Mat, Point2f, Rect, Size, Vector};
+use opencv::objdetect::{CascadeClassifier, CascadeClassifierTrait, CascadeClassifierTraitConst};
+
+use super::{get_detection_image, Detector, DetectorRegistry, FocalPoint, DETECTION_MAX_SIDE};
+use crate::error::ThumborError;
+use crate::settings::{conf, Settings};
+
+const CASCADE_MIN_SIZE: i32 = 20;
+const FEATURES_MAX_CORNERS: i32 = 20;
+const FEATURES_QUALITY_LEVEL: f64 = 0.04;
+const FEATURES_MIN_DISTANCE: f64 = 1.0;
+
+thread_local! {
+    // Loading a cascade parses a big XML file, so each processing thread keeps them
+    static CASCADES: RefCell<HashMap<String, CascadeClassifier>> = RefCell::new(HashMap::new());
+}
+
+fn load_cascade(cascade_path: &str) -> Result<CascadeClassifier, ThumborError> {
+    CascadeClassifier::new(cascade_path)
+        .ok()
+        .filter(|cascade| !cascade.empty().unwrap_or(true))
+        .ok_or_else(|| ThumborError::Internal(format!("Could not load cascade: {}", cascade_path)))
+}
+
+/**
+ * Checks that the cascades of the `face_cascade_path`, `profile_cascade_path` and
+ * `eyes_cascade_path` settings can be loaded. An empty path disables its detector.
+ */
+pub fn validate_cascades(settings: &Settings) -> Result<(), ThumborError> {
+    [&settings.face_cascade_path, &settings.profile_cascade_path, &settings.eyes_cascade_path]
+        .into_iter()
+        .filter(|cascade_path| !cascade_path.is_empty())
+        .try_for_each(|cascade_path| load_cascade(cascade_path).map(|_| ()))
+}
+
+/**
+ * Runs the Haar or LBP cascade on a downscaled copy of the image. Each detected object is a
+ * focal point weighted by its area. An empty `cascade_path` disables the detection.
+ */
+fn detect_with_cascade(image: &Mat, cascade_path: &str) -> Result<Vec<FocalPoint>, ThumborError> {
+    if cascade_path.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let (detection_image, scale) = get_detection_image(image, DETECTION_MAX_SIDE)?;
+    let mut equalized_image = Mat::default();
+    opencv::imgproc::equalize_hist(&detection_image, &mut equalized_image)?;
+
+    let mut objects: Vector<Rect> = Vector::new();
+    CASCADES.with(|cascades| -> Result<(), ThumborError> {
+        let mut cascades = cascades.borrow_mut();
+        let cascade = match cascades.entry(cascade_path.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(load_cascade(cascade_path)?),
+        };
+
+        cascade.detect_multi_scale(
+            &equalized_image,
+            &mut objects,
+            1.1,
+            3,
+            0,
+            Size { width: CASCADE_MIN_SIZE, height: CASCADE_MIN_SIZE },
+            Size::default(),
+        )?;
+        Ok(())
+    })?;
+
+    Ok(objects
+        .iter()
+        .map(|object| {
+            let rect = Rect {
+                x: (object.x as f32 * scale) as i32,
+                y: (object.y as f32 * scale) as i32,
+                width: (object.width as f32 * scale) as i32,
+                height: (object.height as f32 * scale) as i32,
+            };
+            FocalPoint::from_rect(rect, rect.area() as f32)
+        })
+        .collect())
+}
+
+/**
+ * `face`: frontal faces, with the cascade of the `face_cascade_path` setting.
+ */
+pub struct FaceDetector;
+
+impl Detector for FaceDetector {
+    fn detect(&self, image: &Mat) -> Result<Vec<FocalPoint>, ThumborError> {
+        detect_with_cascade(image, &conf().face_cascade_path)
+    }
+}
+
+/**
+ * `profile`: faces seen from the side, with the cascade of the `profile_cascade_path` setting.
+ */
+pub struct ProfileFaceDetector;
+
+impl Detector for ProfileFaceDetector {
+    fn detect(&self, image: &Mat) -> Result<Vec<FocalPoint>, ThumborError> {
+        detect_with_cascade(image, &conf().profile_cascade_path)
+    }
+}
+
+/**
+ * `eyes`: eyes, with the cascade of the `eyes_cascade_path` setting.
+ */
+pub struct EyesDetector;
+
+impl Detector for EyesDetector {
+    fn detect(&self, image: &Mat) -> Result<Vec<FocalPoint>, ThumborError> {
+        detect_with_cascade(image, &conf().eyes_cascade_path)
+    }
+}
+
+/**
+ * `feature`: the strongest corners found with "good features to track" on a downscaled copy of
+ * the image. Each corner is a focal point, stronger corners come first and weigh more.
+ */
+pub struct FeatureDetector;
+
+impl Detector for FeatureDetector {
+    fn detect(&self, image: &Mat) -> Result<Vec<FocalPoint>, ThumborError> {
+        detect_features(image)
+    }
+}
+
+fn detect_features(image: &Mat) -> Result<Vec<FocalPoint>, ThumborError> {
+    let (detection_image, scale) = get_detection_image(image, DETECTION_MAX_SIDE)?;
+
+    let mut corners: Vector<Point2f> = Vector::new();
+    opencv::imgproc::good_features_to_track(
+        &detection_image,
+        &mut corners,
+        FEATURES_MAX_CORNERS,
+        FEATURES_QUALITY_LEVEL,
+        FEATURES_MIN_DISTANCE,
+        &opencv::core::no_array(),
+        3,
+        false,
+        0.04,
+    )?;
+
+    let count = corners.len();
+    Ok(corners
+        .iter()
+        .enumerate()
+        .map(|(index, corner)| {
+            let rect = Rect { x: (corner.x * scale) as i32, y: (corner.y * scale) as i32, width: 1, height: 1 };
+            FocalPoint::from_rect(rect, (count - index) as f32)
+        })
+        .collect())
+}
+
+pub fn register_builtin_detectors(registry: &mut DetectorRegistry) {
+    registry.register("face", FaceDetector);
+    registry.register("feature", FeatureDetector);
+    registry.register("profile", ProfileFaceDetector);
+    registry.register("eyes", EyesDetector);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Point, Scalar, CV_8UC3};
+    use opencv::prelude::MatTraitConstManual; // to get method `.size()` must have this use
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    // Minimal cascade that fires on a bright square centred in a dark window. The real face
+    // cascades are not in the repo, so the tests draw the square as the "face".
+    const TEST_CASCADE_PATH: &str = "./src/images/cascades/test_square.xml";
+    const TEST_FACE: Rect = Rect { x: 200, y: 100, width: 60, height: 60 };
+
+    fn image_with_face() -> Mat {
+        let mut image = Mat::new_rows_cols_with_default(300, 400, CV_8UC3, Scalar::all(0.0)).unwrap();
+        opencv::imgproc::rectangle(&mut image, TEST_FACE, Scalar::all(255.0), opencv::imgproc::FILLED, opencv::imgproc::LINE_8, 0).unwrap();
+        image
+    }
+
+    #[test]
+    fn test_face_detector() {
+        Settings { face_cascade_path: TEST_CASCADE_PATH.to_string(), ..Default::default() }.make_current();
+
+        let focal_points = FaceDetector.detect(&image_with_face()).unwrap();
+        assert!(!focal_points.is_empty());
+        for focal_point in focal_points {
+            let center = focal_point.center();
+            assert!(TEST_FACE.contains(Point { x: center.x as i32, y: center.y as i32 }), "{:?}", focal_point);
+            assert!(focal_point.width >= TEST_FACE.width / 2 && focal_point.width <= TEST_FACE.width * 4);
+            assert!(focal_point.weight > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_face_detector_without_faces() {
+        Settings { face_cascade_path: TEST_CASCADE_PATH.to_string(), ..Default::default() }.make_current();
+        let image = Mat::new_rows_cols_with_default(200, 200, CV_8UC3, Scalar::all(128.0)).unwrap();
+        assert!(FaceDetector.detect(&image).unwrap().is_empty());
+        assert!(detect_with_cascade(&image_with_face(), "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_detect_with_missing_cascade() {
+        assert_eq!(
+            detect_with_cascade(&image_with_face(), "./does-not-exist.xml"),
+            Err(ThumborError::Internal("Could not load cascade: ./does-not-exist.xml".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_validate_cascades() {
+        assert_eq!(validate_cascades(&Settings::default()), Ok(()));
+        assert_eq!(validate_cascades(&Settings { face_cascade_path: TEST_CASCADE_PATH.to_string(), ..Default::default() }), Ok(()));
+        assert_eq!(
+            validate_cascades(&Settings { eyes_cascade_path: "./does-not-exist.xml".to_string(), ..Default::default() }),
+            Err(ThumborError::Internal("Could not load cascade: ./does-not-exist.xml".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_feature_detector() {
+        let image = opencv::imgcodecs::imread("./src/images/big.jpg", opencv::imgcodecs::IMREAD_COLOR).unwrap();
+        let size = image.size().unwrap();
+
+        let focal_points = FeatureDetector.detect(&image).unwrap();
+        assert!(!focal_points.is_empty());
+        assert!(focal_points.len() <= FEATURES_MAX_CORNERS as usize);
+        for focal_point in &focal_points {
+            assert!(focal_point.x >= 0 && focal_point.x < size.width);
+            assert!(focal_point.y >= 0 && focal_point.y < size.height);
+        }
+
+        let flat_image = Mat::new_rows_cols_with_default(200, 200, CV_8UC3, Scalar::all(128.0)).unwrap();
+        assert!(FeatureDetector.detect(&flat_image).unwrap().is_empty());
+    }
+}
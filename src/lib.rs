@@ -1,7 +1,13 @@
 pub mod calc;
 pub mod controller;
+pub mod error;
+pub mod filters;
 pub mod service;
 pub mod security;
 pub mod settings;
 pub mod image;
+pub mod meta;
+pub mod registry;
 pub mod url_props;
+pub mod url_parser;
+pub mod url_builder;
@@ -1,12 +1,20 @@
 extern crate lazy_static;
 
-use thumbor_rust::{controller, settings::{conf, Settings}};
+use thumbor_rust::{controller, error::ThumborError, image::{encoder::EncodeOptions, smart}, service::loader, settings::{conf, Settings}};
 use actix_web::{App, HttpServer};
 
+fn invalid_settings(error: ThumborError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, error.to_string())
+}
+
 #[actix_web::main] // or #[tokio::main]
 async fn main() -> std::io::Result<()> {
     Settings::start();
     println!("Settings {:?}", conf());
+    // Configuration errors stop the server here instead of failing every request
+    smart::registry().validate_settings(&conf()).map_err(invalid_settings)?;
+    loader::loader_chain().map_err(invalid_settings)?;
+    EncodeOptions::validate_settings(&conf()).map_err(invalid_settings)?;
     let n_workers = num_cpus::get() * 2;
     println!("Starting server with {} workers", n_workers);
 
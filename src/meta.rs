@@ -0,0 +1,219 @@
+use opencv::core::{Mat, Rect};
+use opencv::prelude::MatTraitConstManual; // to get method `.size()` must have this use
+use serde::Serialize;
+
+use crate::calc;
+use crate::error::ThumborError;
+use crate::image::image_manipulator;
+use crate::image::smart::{self, DetectorRegistry};
+use crate::url_props::UrlProps;
+
+/**
+ * Response of the `/meta` urls, with the same layout as Thumbor:
+ * `{"thumbor": {"source": {...}, "operations": [...], "target": {...}}}`
+ */
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Meta {
+    pub thumbor: ThumborMeta,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ThumborMeta {
+    pub source: SourceMeta,
+    pub operations: Vec<Operation>,
+    pub target: TargetMeta,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SourceMeta {
+    pub url: String,
+    pub width: i32,
+    pub height: i32,
+    #[serde(rename = "frameCount")]
+    pub frame_count: i32,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TargetMeta {
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Operation {
+    Crop { left: i32, top: i32, right: i32, bottom: i32 },
+    Resize { width: i32, height: i32 },
+    FlipHorizontally,
+    FlipVertically,
+    Filter { name: String, args: Vec<String> },
+}
+
+impl Operation {
+    fn crop(rect: Rect) -> Operation {
+        Operation::Crop {
+            left: rect.x,
+            top: rect.y,
+            right: rect.x + rect.width,
+            bottom: rect.y + rect.height,
+        }
+    }
+}
+
+/**
+ * Describes every operation the image goes through for the url, without applying them.
+ * Crop boxes are relative to the image at the step they run. The final crop uses the same focal
+ * points as the image itself, see `smart::get_focal_points`.
+ */
+pub fn build_meta(
+    url_props: &UrlProps,
+    image: &Mat,
+    detector_registry: &DetectorRegistry,
+    source_digest: Option<&str>,
+) -> Result<Meta, ThumborError> {
+    let source_size = image.size()?;
+    let region = image_manipulator::get_source_region(image, url_props)?;
+    let mut operations: Vec<Operation> = region.crops.iter().map(|rect| Operation::crop(*rect)).collect();
+    let current_size = region.image.size()?;
+
+    let mut url_props = url_props.clone();
+    let target_size = calc::get_url_target_size(current_size, &url_props, url_props.allow_upscale());
+    url_props.width = target_size.width;
+    url_props.height = target_size.height;
+
+    let resize_size = image_manipulator::get_resize_size(current_size, &url_props);
+    operations.push(Operation::Resize { width: resize_size.width, height: resize_size.height });
+
+    let final_size = match url_props.fit_in {
+        Some(_) => resize_size,
+        None => {
+            let focal_points = smart::get_focal_points(&region.image, &url_props, region.origin, detector_registry, source_digest)?;
+            let rect = image_manipulator::get_target_crop_rect(&url_props, current_size, &focal_points)?;
+            operations.push(Operation::crop(rect));
+            rect.size()
+        }
+    };
+
+    if url_props.flip.horizontal {
+        operations.push(Operation::FlipHorizontally);
+    }
+
+    if url_props.flip.vertical {
+        operations.push(Operation::FlipVertically);
+    }
+
+    for filter in &url_props.filters {
+        operations.push(Operation::Filter { name: filter.name.clone(), args: filter.args.clone() });
+    }
+
+    Ok(Meta {
+        thumbor: ThumborMeta {
+            source: SourceMeta {
+                url: url_props.filename.clone(),
+                width: source_size.width,
+                height: source_size.height,
+                frame_count: 1,
+            },
+            operations,
+            target: TargetMeta {
+                width: final_size.width,
+                height: final_size.height,
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+    use crate::url_parser;
+    use opencv::core::Size;
+
+    fn read_image(filename: &str) -> Mat {
+        opencv::imgcodecs::imread(&format!("./src/images/{}", filename), opencv::imgcodecs::IMREAD_COLOR).unwrap()
+    }
+
+    #[test]
+    fn test_build_meta_with_crop_by_alignment() {
+        let image = read_image("big.jpg");
+        let size = image.size().unwrap();
+        let url_props = url_parser::parse("meta/-300x200/left/big.jpg").unwrap();
+        let resized = calc::get_new_size_respecting_aspect_ratio(size, Size { width: 300, height: 200 });
+
+        let meta = build_meta(&url_props, &image, &DetectorRegistry::with_builtin_detectors(), None).unwrap();
+
+        assert_eq!(meta.thumbor.source, SourceMeta {
+            url: "big.jpg".to_string(),
+            width: size.width,
+            height: size.height,
+            frame_count: 1,
+        });
+        assert_eq!(meta.thumbor.operations, vec![
+            Operation::Resize { width: resized.width, height: resized.height },
+            Operation::Crop {
+                left: 0,
+                top: (resized.height - 200) / 2,
+                right: 300,
+                bottom: (resized.height - 200) / 2 + 200,
+            },
+            Operation::FlipHorizontally,
+        ]);
+        assert_eq!(meta.thumbor.target, TargetMeta { width: 300, height: 200 });
+    }
+
+    #[test]
+    fn test_build_meta_with_manual_crop_and_fit_in() {
+        let image = read_image("big.jpg");
+        let url_props = url_parser::parse("meta/0x0:100x50/fit-in/40x40/filters:quality(80)/big.jpg").unwrap();
+
+        let meta = build_meta(&url_props, &image, &DetectorRegistry::with_builtin_detectors(), None).unwrap();
+
+        assert_eq!(meta.thumbor.operations, vec![
+            Operation::Crop { left: 0, top: 0, right: 100, bottom: 50 },
+            Operation::Resize { width: 40, height: 20 },
+            Operation::Filter { name: "quality".to_string(), args: vec!["80".to_string()] },
+        ]);
+        assert_eq!(meta.thumbor.target, TargetMeta { width: 40, height: 20 });
+    }
+
+    #[test]
+    fn test_build_meta_with_focal() {
+        let image = read_image("big.jpg");
+        let url_props = url_parser::parse("meta/100x300/filters:focal(1960x2000:2360x2400)/big.jpg").unwrap();
+
+        let meta = build_meta(&url_props, &image, &DetectorRegistry::with_builtin_detectors(), None).unwrap();
+
+        // The 3456x5184 source is resized to 200x300, the focal centre (2160, 2200) is at x = 125
+        assert_eq!(meta.thumbor.operations, vec![
+            Operation::Resize { width: 200, height: 300 },
+            Operation::Crop { left: 75, top: 0, right: 175, bottom: 300 },
+            Operation::Filter { name: "focal".to_string(), args: vec!["1960x2000:2360x2400".to_string()] },
+        ]);
+        assert_eq!(meta.thumbor.target, TargetMeta { width: 100, height: 300 });
+    }
+
+    #[test]
+    fn test_build_meta_with_focal_after_manual_crop() {
+        let image = read_image("big.jpg");
+        let url_props = url_parser::parse("meta/456x1000:3456x4000/50x100/filters:focal(1256x2400:1456x2600)/big.jpg").unwrap();
+
+        let meta = build_meta(&url_props, &image, &DetectorRegistry::with_builtin_detectors(), None).unwrap();
+
+        // The focal rect is in source coordinates, its centre x = 1356 is at x = 900 in the crop, 30 once resized
+        assert_eq!(meta.thumbor.operations[..3], [
+            Operation::Crop { left: 456, top: 1000, right: 3456, bottom: 4000 },
+            Operation::Resize { width: 100, height: 100 },
+            Operation::Crop { left: 5, top: 0, right: 55, bottom: 100 },
+        ]);
+    }
+
+    #[test]
+    fn test_meta_json_layout() {
+        let operations = vec![Operation::Resize { width: 10, height: 20 }, Operation::FlipVertically];
+        assert_eq!(
+            serde_json::to_string(&operations).unwrap(),
+            r#"[{"type":"resize","width":10,"height":20},{"type":"flip_vertically"}]"#
+        );
+    }
+}
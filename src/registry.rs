@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/**
+ * Implementations of a trait by name, like the filters or the smart detectors.
+ * Clones share the implementations, so a request can keep a copy of the global registry.
+ */
+pub struct Registry<T: ?Sized> {
+    entries: HashMap<String, Arc<T>>,
+}
+
+impl<T: ?Sized> Default for Registry<T> {
+    fn default() -> Self {
+        Registry { entries: HashMap::new() }
+    }
+}
+
+impl<T: ?Sized> Clone for Registry<T> {
+    fn clone(&self) -> Self {
+        Registry { entries: self.entries.clone() }
+    }
+}
+
+impl<T: ?Sized> Registry<T> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /**
+     * Adds an implementation by name. One with the same name is replaced.
+     */
+    pub fn insert(&mut self, name: &str, entry: Arc<T>) {
+        self.entries.insert(name.to_string(), entry);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<T>> {
+        self.entries.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    #[test]
+    fn test_registry() {
+        let mut registry: Registry<str> = Registry::new();
+        assert!(registry.is_empty());
+
+        registry.insert("a", Arc::from("first"));
+        registry.insert("b", Arc::from("second"));
+        registry.insert("a", Arc::from("replaced"));
+        let copy = registry.clone();
+
+        assert_eq!(copy.len(), 2);
+        assert_eq!(copy.get("a").map(|entry| entry.as_ref()), Some("replaced"));
+        assert!(copy.contains("b"));
+        assert!(!copy.contains("c"));
+    }
+}
@@ -14,25 +14,38 @@ pub enum KeyError {
     InvalidKeyLength(#[from] sha1::digest::InvalidLength),
 }
 
+/**
+ * Returns the url-safe base64 of the HMAC-SHA1 of the path with the given secret key.
+ */
+pub fn sign(secret_key: &str, path: &str) -> Result<String, KeyError> {
+    let mut mac = HmacSha1::new_from_slice(secret_key.as_bytes())?;
+    mac.update(path.as_bytes());
+
+    let hmac_base64 = general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+    Ok(hmac_base64.replace('+', "-").replace('/', "_"))
+}
+
 pub fn get_key_by_path(path: String) -> Result<String, KeyError> {
     if conf().secret_key.is_empty() {
         return Ok("unsafe".to_string());
     }
 
-    let mut mac = HmacSha1::new_from_slice(conf().secret_key.as_bytes())?;
-    mac.update(path.as_bytes());
-
-    let hmac_base64 = general_purpose::STANDARD.encode(mac.finalize().into_bytes());
-    Ok(hmac_base64.replace('+', "-").replace('/', "_"))
+    sign(&conf().secret_key, &path)
 }
 
 pub fn is_valid_key(path: String) -> bool {
     let parts: Vec<&str> = path.split('/').collect();
+    if parts.len() < 3 {
+        return false;
+    }
 
     let key = parts[1];
     let uri = parts[2..].join("/");
 
-    get_key_by_path(uri).unwrap() == key
+    match get_key_by_path(uri) {
+        Ok(expected_key) => expected_key == key,
+        Err(_) => false,
+    }
 }
 
 #[cfg(test)]
@@ -93,4 +106,16 @@ mod tests {
         let path = "my-invalid-key/50x50/big.jpg".to_string();
         assert_eq!(is_valid_key(path), false);
     }
+
+    #[test]
+    pub fn is_valid_key_without_path() {
+        Settings {
+            secret_key: "ANY_KEY".to_string(),
+            ..Default::default()
+        }.make_current();
+
+        assert!(!is_valid_key("".to_string()));
+        assert!(!is_valid_key("/".to_string()));
+        assert!(!is_valid_key("/unsafe".to_string()));
+    }
 }
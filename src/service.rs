@@ -1,4 +1,6 @@
 // Add folder service with multiple functions
 // functions to image processing
 pub mod image;
-pub mod gateway;
\ No newline at end of file
+pub mod loader;
+pub mod gateway;
+pub mod processing_pool;
\ No newline at end of file
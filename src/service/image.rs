@@ -1,44 +1,171 @@
+use std::time::SystemTime;
+
+use actix_web::http::header::HeaderMap;
 use opencv::{core::Mat};
-use reqwest::{get, header::CONTENT_TYPE};
-use mime_guess::MimeGuess;
+use opencv::prelude::MatTraitConst;
+
+use crate::error::ThumborError;
+use crate::image::encoder::ImageFormat;
+use crate::image::image_manipulator;
+use crate::image::metadata::{self, ImageMetadata};
+use crate::service::loader::{self, Loader};
+use crate::settings::conf;
+
+/**
+ * Bytes of the source image, before decoding, and what the loader knows about them.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceImage {
+    pub mime_type: String,
+    pub bytes: Vec<u8>,
+    pub last_modified: Option<SystemTime>,
+    pub etag: Option<String>,
+}
+
+impl SourceImage {
+    pub fn new(mime_type: &str, bytes: Vec<u8>) -> Self {
+        SourceImage { mime_type: mime_type.to_string(), bytes, ..Default::default() }
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+}
 
 pub struct ImageWithType {
     pub mime_type: String,
+    pub format: ImageFormat,
     pub image: Mat,
+    pub metadata: ImageMetadata,
 }
 
-async fn load_image_from_url(filename: &mut String) -> Result<ImageWithType, Box<dyn std::error::Error>> {
-    let resp = get(filename.to_string()).await?;    
+/**
+ * Loads the source image with the loaders of the `loaders` setting, in order.
+ */
+pub async fn get_image(path: &str, request_headers: &HeaderMap) -> Result<SourceImage, ThumborError> {
+    loader::loader_chain()?.load(path, request_headers).await
+}
 
-    if resp.status().is_success() {
-        let headers = resp.headers().clone();
-        let content_type = headers
-            .get(CONTENT_TYPE)
-            .and_then(|header| header.to_str().ok())
-            .unwrap_or("type/jpeg");
+/**
+ * Returns whether the PNG or WebP header declares an alpha channel.
+ */
+fn has_alpha(bytes: &[u8]) -> bool {
+    match ImageFormat::detect(bytes) {
+        Some(ImageFormat::Png) => {
+            // Grayscale or truecolour with alpha, or a transparency chunk before the image data
+            let color_type = bytes.get(25).copied().unwrap_or_default();
+            let header_end = bytes.windows(4).position(|chunk| chunk == b"IDAT").unwrap_or(bytes.len());
+            color_type == 4 || color_type == 6 || bytes[..header_end].windows(4).any(|chunk| chunk == b"tRNS")
+        }
+        Some(ImageFormat::Webp) => match bytes.get(12..16) {
+            Some(b"VP8X") => bytes.get(20).map(|flags| flags & 0x10 != 0).unwrap_or(false),
+            Some(b"VP8L") => bytes.get(24).map(|header| header & 0x10 != 0).unwrap_or(false),
+            _ => false,
+        },
+        _ => false,
+    }
+}
 
-        let image_data = resp.bytes().await?.to_vec();
-        let mat = Mat::from_slice(&image_data)?;
-        let img = opencv::imgcodecs::imdecode(&mat, opencv::imgcodecs::IMREAD_COLOR)?;
+/**
+ * Decodes the source bytes. It is CPU-bound, so it runs in the processing pool.
+ * Sources with alpha are decoded to BGRA, every other one to BGR. The image is rotated
+ * following the EXIF orientation, so every size refers to the upright image. The encoder
+ * never writes the orientation back, so the output is not rotated twice.
+ * The EXIF and ICC profile are kept following the `preserve_exif` and `preserve_icc` settings.
+ */
+pub fn decode_image(source: SourceImage) -> Result<ImageWithType, ThumborError> {
+    let mat = Mat::from_slice(&source.bytes)?;
+    // OpenCV only applies the orientation to some formats, so it is always applied here
+    let flags = match has_alpha(&source.bytes) {
+        true => opencv::imgcodecs::IMREAD_UNCHANGED,
+        false => opencv::imgcodecs::IMREAD_COLOR | opencv::imgcodecs::IMREAD_IGNORE_ORIENTATION,
+    };
+    let mut image = opencv::imgcodecs::imdecode(&mat, flags)?;
+    if image.empty() {
+        return Err(ThumborError::Decode("Invalid image data".to_string()));
+    }
 
-        return Ok(ImageWithType { image: img, mime_type: content_type.to_string() })
+    // IMREAD_UNCHANGED keeps 16 bit depth and may return a single gray channel
+    if image.depth() != opencv::core::CV_8U {
+        let mut image_8u = Mat::default();
+        image.convert_to(&mut image_8u, opencv::core::CV_8U, 1.0 / 256.0, 0.0)?;
+        image = image_8u;
+    }
+    if image.channels() == 1 {
+        let mut image_bgr = Mat::default();
+        opencv::imgproc::cvt_color(&image, &mut image_bgr, opencv::imgproc::COLOR_GRAY2BGR, 0)?;
+        image = image_bgr;
+    }
+
+    if let Some(orientation) = metadata::read_orientation(&source.bytes) {
+        image = image_manipulator::orient(&image, orientation)?;
     }
-    Ok(ImageWithType { image: Mat::default(), mime_type: "type/jpeg".to_string() })
-}
 
-fn load_image_from_file(filename: &str) -> Result<ImageWithType, opencv::Error> {
-    let path = format!("./src/images/{}", filename);
-    let img = opencv::imgcodecs::imread(&path, opencv::imgcodecs::IMREAD_COLOR)?;
-    let mime = MimeGuess::from_path(path).first_or_octet_stream();
+    let format = ImageFormat::detect(&source.bytes)
+        .or_else(|| ImageFormat::from_mime_type(&source.mime_type))
+        .unwrap_or(ImageFormat::Jpeg);
 
-    Ok(ImageWithType { image: img, mime_type: mime.to_string() })
+    let mut image_metadata = metadata::read_metadata(&source.bytes);
+    if !conf().preserve_exif {
+        image_metadata.exif = None;
+    }
+    if !conf().preserve_icc {
+        image_metadata.icc = None;
+    }
+
+    Ok(ImageWithType { image, format, mime_type: source.mime_type, metadata: image_metadata })
 }
 
-pub async fn get_image(filename: &str) -> ImageWithType {
-    if filename.starts_with("http://") || filename.starts_with("https://") {
-        let img = load_image_from_url(&mut filename.to_string()).await.unwrap();
-        return img;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::prelude::MatTraitConstManual; // to get method `.size()` must have this use
+
+    fn png_header(color_type: u8) -> Vec<u8> {
+        let mut bytes = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        bytes.extend([0, 0, 0, 10, 0, 0, 0, 10, 8, color_type, 0, 0, 0]);
+        bytes
+    }
+
+    #[test]
+    fn test_has_alpha() {
+        assert!(has_alpha(&png_header(6)));
+        assert!(has_alpha(&png_header(4)));
+        assert!(!has_alpha(&png_header(2)));
+
+        let mut palette_with_transparency = png_header(3);
+        palette_with_transparency.extend(b"\x00\x00\x00\x01tRNS\x00\x00\x00\x00\x00\x00\x00\x00IDAT");
+        assert!(has_alpha(&palette_with_transparency));
+
+        assert!(has_alpha(b"RIFF\x00\x00\x00\x00WEBPVP8X\x0a\x00\x00\x00\x10"));
+        assert!(!has_alpha(b"RIFF\x00\x00\x00\x00WEBPVP8 \x0a\x00\x00\x00\x10"));
+        assert!(!has_alpha(&std::fs::read("./src/images/sun.jpg").unwrap()));
     }
 
-    load_image_from_file(filename).unwrap()
-}
\ No newline at end of file
+    #[test]
+    fn test_decode_image_keeps_alpha() {
+        let image = Mat::new_rows_cols_with_default(10, 10, opencv::core::CV_8UC4, opencv::core::Scalar::new(255.0, 0.0, 0.0, 0.0)).unwrap();
+        let mut bytes: opencv::core::Vector<u8> = opencv::core::Vector::new();
+        opencv::imgcodecs::imencode(".png", &image, &mut bytes, &opencv::core::Vector::new()).unwrap();
+
+        let decoded = decode_image(SourceImage::new("image/png", bytes.to_vec())).unwrap();
+        assert_eq!(decoded.format, ImageFormat::Png);
+        assert_eq!(decoded.image.channels(), 4);
+
+        let decoded = decode_image(SourceImage::new("image/jpeg", std::fs::read("./src/images/sun.jpg").unwrap())).unwrap();
+        assert_eq!(decoded.image.channels(), 3);
+    }
+
+    #[test]
+    fn test_decode_image_applies_orientation() {
+        let jpeg = std::fs::read("./src/images/sun.jpg").unwrap();
+        let decode = |bytes: Vec<u8>| decode_image(SourceImage::new("image/jpeg", bytes)).unwrap().image;
+        let size = decode(jpeg.clone()).size().unwrap();
+
+        let rotated_size = decode(metadata::tests::jpeg_with_orientation(&jpeg, 6)).size().unwrap();
+        assert_eq!((rotated_size.width, rotated_size.height), (size.height, size.width));
+
+        let upside_down_size = decode(metadata::tests::jpeg_with_orientation(&jpeg, 3)).size().unwrap();
+        assert_eq!((upside_down_size.width, upside_down_size.height), (size.width, size.height));
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::Arc;
+
+use actix_web::http::header::HeaderMap;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+
+use crate::error::ThumborError;
+use crate::service::image::SourceImage;
+use crate::settings::{Settings, CONF_S};
+
+pub mod data_uri;
+pub mod file;
+pub mod http;
+
+lazy_static! {
+    // Built once, only the forwarded headers change with the request
+    static ref LOADER_CHAIN: Result<LoaderChain, ThumborError> = LoaderChain::from_settings(&CONF_S);
+}
+
+/**
+ * Reads the bytes of a source image from where the image path of the url points.
+ */
+#[async_trait]
+pub trait Loader: Send + Sync {
+    /**
+     * Returns whether the path is one the loader understands. The chain skips the other loaders.
+     */
+    fn can_load(&self, path: &str) -> bool;
+
+    /**
+     * Fails with `SourceNotFound` when the image does not exist, so the chain tries the next loader.
+     * `request_headers` are the headers of the request for the image, e.g. to forward to the origin.
+     */
+    async fn load(&self, path: &str, request_headers: &HeaderMap) -> Result<SourceImage, ThumborError>;
+}
+
+/**
+ * Loaders tried in order. The first one that can load the path and finds the image wins,
+ * e.g. `["file", "http"]` tries the file loader and then the HTTP one.
+ */
+#[derive(Default, Clone)]
+pub struct LoaderChain {
+    loaders: Vec<Arc<dyn Loader>>,
+}
+
+impl LoaderChain {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add(mut self, loader: impl Loader + 'static) -> Self {
+        self.loaders.push(Arc::new(loader));
+        self
+    }
+
+    /**
+     * Builds the chain from loader names: `data`, `file` and `http`, each configured by the settings.
+     */
+    pub fn from_names(names: &[String], settings: &Settings) -> Result<Self, ThumborError> {
+        names.iter().try_fold(LoaderChain::new(), |chain, name| match name.as_str() {
+            "data" => Ok(chain.add(data_uri::DataUriLoader)),
+            "file" => Ok(chain.add(file::FileLoader::from_settings(settings))),
+            "http" => Ok(chain.add(http::HttpLoader::from_settings(settings)?)),
+            _ => Err(ThumborError::Internal(format!("Unknown loader: {}", name))),
+        })
+    }
+
+    /**
+     * The chain of the `loaders` setting.
+     */
+    pub fn from_settings(settings: &Settings) -> Result<Self, ThumborError> {
+        LoaderChain::from_names(&settings.loaders, settings)
+    }
+}
+
+/**
+ * The chain of the `loaders` setting, shared by every request. Fails when the setting is invalid,
+ * which the server checks at startup.
+ */
+pub fn loader_chain() -> Result<&'static LoaderChain, ThumborError> {
+    LOADER_CHAIN.as_ref().map_err(|error| error.clone())
+}
+
+#[async_trait]
+impl Loader for LoaderChain {
+    fn can_load(&self, path: &str) -> bool {
+        self.loaders.iter().any(|loader| loader.can_load(path))
+    }
+
+    async fn load(&self, path: &str, request_headers: &HeaderMap) -> Result<SourceImage, ThumborError> {
+        let mut last_error = ThumborError::SourceNotFound(path.to_string());
+        for loader in self.loaders.iter().filter(|loader| loader.can_load(path)) {
+            match loader.load(path, request_headers).await {
+                Err(ThumborError::SourceNotFound(message)) => last_error = ThumborError::SourceNotFound(message),
+                result => return result,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    struct FixedLoader {
+        prefix: &'static str,
+        result: Result<SourceImage, ThumborError>,
+    }
+
+    #[async_trait]
+    impl Loader for FixedLoader {
+        fn can_load(&self, path: &str) -> bool {
+            path.starts_with(self.prefix)
+        }
+
+        async fn load(&self, _path: &str, _request_headers: &HeaderMap) -> Result<SourceImage, ThumborError> {
+            self.result.clone()
+        }
+    }
+
+    fn found(mime_type: &str) -> FixedLoader {
+        FixedLoader { prefix: "", result: Ok(SourceImage::new(mime_type, vec![1, 2, 3])) }
+    }
+
+    fn not_found() -> FixedLoader {
+        FixedLoader { prefix: "", result: Err(ThumborError::SourceNotFound("missing".to_string())) }
+    }
+
+    #[actix_web::test]
+    async fn test_chain_falls_back_when_not_found() {
+        let chain = LoaderChain::new().add(not_found()).add(found("image/png")).add(found("image/gif"));
+        assert_eq!(chain.load("image.png", &HeaderMap::new()).await.unwrap().mime_type, "image/png");
+    }
+
+    #[actix_web::test]
+    async fn test_chain_skips_loaders_that_cannot_load() {
+        let chain = LoaderChain::new()
+            .add(FixedLoader { prefix: "http://", result: Ok(SourceImage::new("image/gif", vec![])) })
+            .add(found("image/png"));
+        assert_eq!(chain.load("image.png", &HeaderMap::new()).await.unwrap().mime_type, "image/png");
+        assert_eq!(chain.load("http://example.com/a.gif", &HeaderMap::new()).await.unwrap().mime_type, "image/gif");
+    }
+
+    #[actix_web::test]
+    async fn test_chain_stops_at_other_errors() {
+        let failing = FixedLoader { prefix: "", result: Err(ThumborError::Upstream("timeout".to_string())) };
+        let chain = LoaderChain::new().add(failing).add(found("image/png"));
+        assert!(matches!(chain.load("image.png", &HeaderMap::new()).await, Err(ThumborError::Upstream(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_chain_without_loaders() {
+        let chain = LoaderChain::new().add(not_found());
+        assert_eq!(chain.load("image.png", &HeaderMap::new()).await, Err(ThumborError::SourceNotFound("missing".to_string())));
+        assert_eq!(LoaderChain::new().load("image.png", &HeaderMap::new()).await, Err(ThumborError::SourceNotFound("image.png".to_string())));
+    }
+
+    #[test]
+    fn test_from_names() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<String>>();
+        let settings = Settings::default();
+        assert!(LoaderChain::from_names(&names(&["data", "file", "http"]), &settings).is_ok());
+        assert!(matches!(LoaderChain::from_names(&names(&["file", "s3"]), &settings), Err(ThumborError::Internal(_))));
+    }
+
+    #[test]
+    fn test_loader_chain_of_default_settings() {
+        assert!(loader_chain().is_ok());
+    }
+}
@@ -0,0 +1,87 @@
+use actix_web::http::header::HeaderMap;
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+
+use super::Loader;
+use crate::error::ThumborError;
+use crate::service::image::SourceImage;
+
+/**
+ * Loads images embedded in the url as `data:[<mime type>][;base64],<data>` (RFC 2397).
+ */
+pub struct DataUriLoader;
+
+/**
+ * Returns the mime type and the bytes of a data URI. Data that is not base64 is percent-encoded.
+ */
+pub fn parse_data_uri(uri: &str) -> Result<(String, Vec<u8>), ThumborError> {
+    let invalid = || ThumborError::BadRequest("Invalid data URI".to_string());
+    let (header, data) = uri.strip_prefix("data:").and_then(|rest| rest.split_once(',')).ok_or_else(invalid)?;
+
+    let mut parameters = header.split(';');
+    let mime_type = match parameters.next().unwrap_or_default().trim() {
+        "" => "application/octet-stream".to_string(),
+        mime_type => mime_type.to_lowercase(),
+    };
+    let is_base64 = parameters.any(|parameter| parameter.trim().eq_ignore_ascii_case("base64"));
+
+    let bytes = match is_base64 {
+        true => {
+            // Urls may keep the padding and line breaks percent-encoded
+            let data = percent_encoding::percent_decode_str(data).decode_utf8().map_err(|_| invalid())?;
+            let data: String = data.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+            general_purpose::STANDARD.decode(data).map_err(|_| invalid())?
+        }
+        false => percent_encoding::percent_decode_str(data).collect(),
+    };
+    Ok((mime_type, bytes))
+}
+
+#[async_trait]
+impl Loader for DataUriLoader {
+    fn can_load(&self, path: &str) -> bool {
+        path.starts_with("data:")
+    }
+
+    async fn load(&self, path: &str, _request_headers: &HeaderMap) -> Result<SourceImage, ThumborError> {
+        let (mime_type, bytes) = parse_data_uri(path)?;
+        Ok(SourceImage::new(&mime_type, bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    #[test]
+    fn test_parse_base64_data_uri() {
+        assert_eq!(parse_data_uri("data:image/gif;base64,R0lGODlh").unwrap(), ("image/gif".to_string(), b"GIF89a".to_vec()));
+        assert!(parse_data_uri("data:image/gif;base64,R0lGODdh%3D%3D").is_err());
+        assert_eq!(parse_data_uri("data:;base64,AAE=").unwrap(), ("application/octet-stream".to_string(), vec![0, 1]));
+    }
+
+    #[test]
+    fn test_parse_percent_encoded_data_uri() {
+        assert_eq!(parse_data_uri("data:text/plain,a%20b").unwrap(), ("text/plain".to_string(), b"a b".to_vec()));
+    }
+
+    #[test]
+    fn test_parse_invalid_data_uri() {
+        assert_eq!(parse_data_uri("data:image/png;base64"), Err(ThumborError::BadRequest("Invalid data URI".to_string())));
+        assert_eq!(parse_data_uri("image.png"), Err(ThumborError::BadRequest("Invalid data URI".to_string())));
+        assert!(parse_data_uri("data:image/png;base64,not base64!").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_load_data_uri() {
+        let bytes = std::fs::read("./src/images/sun.jpg").unwrap();
+        let uri = format!("data:image/jpeg;base64,{}", general_purpose::STANDARD.encode(&bytes));
+
+        assert!(DataUriLoader.can_load(&uri));
+        assert!(!DataUriLoader.can_load("sun.jpg"));
+        let source = DataUriLoader.load(&uri, &HeaderMap::new()).await.unwrap();
+        assert_eq!(source.mime_type, "image/jpeg");
+        assert_eq!(source.bytes, bytes);
+    }
+}
@@ -0,0 +1,192 @@
+use std::path::{Component, Path, PathBuf};
+
+use actix_web::http::header::HeaderMap;
+use async_trait::async_trait;
+use mime_guess::MimeGuess;
+
+use super::Loader;
+use crate::error::ThumborError;
+use crate::service::image::SourceImage;
+use crate::settings::Settings;
+
+/**
+ * Loads images from a local directory. The image path of the url is relative to it and cannot
+ * point outside of it.
+ */
+#[derive(Clone)]
+pub struct FileLoader {
+    root_path: PathBuf,
+    follow_symlinks: bool,
+}
+
+fn io_error(path: &str, error: std::io::Error) -> ThumborError {
+    match error.kind() {
+        std::io::ErrorKind::NotFound => ThumborError::SourceNotFound(path.to_string()),
+        _ => ThumborError::Internal(error.to_string()),
+    }
+}
+
+impl FileLoader {
+    pub fn new(root_path: PathBuf, follow_symlinks: bool) -> Self {
+        FileLoader { root_path, follow_symlinks }
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        FileLoader::new(PathBuf::from(&settings.file_loader_root_path), settings.file_loader_follow_symlinks)
+    }
+
+    /**
+     * Returns the file of the path. actix already percent-decoded it, so it is used as is, e.g. `100%25.jpg`
+     * is a file with this name. Paths with `..` or absolute paths are rejected
+     * before touching the disk, then the canonical file must still be in the root. Symlinks are
+     * rejected unless `follow_symlinks`, and even then their target must be in the root.
+     */
+    pub fn resolve_path(&self, path: &str) -> Result<PathBuf, ThumborError> {
+        let forbidden = || ThumborError::Forbidden(format!("Path outside of the loader root: {}", path));
+        if path.contains('\0') {
+            return Err(ThumborError::BadRequest(format!("Invalid path: {}", path)));
+        }
+
+        let mut relative_path = PathBuf::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => relative_path.push(name),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => return Err(forbidden()),
+            }
+        }
+
+        let root_path = self.root_path.canonicalize().map_err(|error| ThumborError::Internal(error.to_string()))?;
+        if !self.follow_symlinks {
+            let mut current = root_path.clone();
+            for name in relative_path.iter() {
+                current.push(name);
+                let metadata = std::fs::symlink_metadata(&current).map_err(|error| io_error(path, error))?;
+                if metadata.file_type().is_symlink() {
+                    return Err(forbidden());
+                }
+            }
+        }
+
+        let file_path = root_path.join(&relative_path).canonicalize().map_err(|error| io_error(path, error))?;
+        if !file_path.starts_with(&root_path) {
+            return Err(forbidden());
+        }
+        Ok(file_path)
+    }
+
+    fn read(&self, path: &str) -> Result<SourceImage, ThumborError> {
+        let file_path = self.resolve_path(path)?;
+        let bytes = std::fs::read(&file_path).map_err(|error| io_error(path, error))?;
+        let metadata = std::fs::metadata(&file_path).map_err(|error| io_error(path, error))?;
+
+        Ok(SourceImage {
+            mime_type: MimeGuess::from_path(&file_path).first_or_octet_stream().to_string(),
+            bytes,
+            last_modified: metadata.modified().ok(),
+            etag: None,
+        })
+    }
+}
+
+#[async_trait]
+impl Loader for FileLoader {
+    fn can_load(&self, path: &str) -> bool {
+        !path.contains("://") && !path.starts_with("data:")
+    }
+
+    async fn load(&self, path: &str, _request_headers: &HeaderMap) -> Result<SourceImage, ThumborError> {
+        let loader = self.clone();
+        let owned_path = path.to_string();
+
+        actix_web::web::block(move || loader.read(&owned_path))
+            .await
+            .map_err(|error| ThumborError::Internal(error.to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    fn loader() -> FileLoader {
+        FileLoader::new(PathBuf::from("./src/images"), false)
+    }
+
+    #[test]
+    fn test_can_load() {
+        assert!(loader().can_load("big.jpg"));
+        assert!(loader().can_load("folder/big.jpg"));
+        assert!(!loader().can_load("http://example.com/big.jpg"));
+        assert!(!loader().can_load("data:image/png;base64,AAAA"));
+    }
+
+    #[actix_web::test]
+    async fn test_load() {
+        let source = loader().load("sun.jpg", &HeaderMap::new()).await.unwrap();
+        assert_eq!(source.mime_type, "image/jpeg");
+        assert_eq!(source.bytes, std::fs::read("./src/images/sun.jpg").unwrap());
+        assert!(source.last_modified.is_some());
+
+        assert_eq!(loader().load("./sun.jpg", &HeaderMap::new()).await.unwrap().mime_type, "image/jpeg");
+    }
+
+    #[actix_web::test]
+    async fn test_load_missing_file() {
+        assert_eq!(loader().load("missing.jpg", &HeaderMap::new()).await, Err(ThumborError::SourceNotFound("missing.jpg".to_string())));
+    }
+
+    #[test]
+    fn test_resolve_path_rejects_traversal() {
+        for path in [
+            "../Cargo.toml",
+            "../../etc/passwd",
+            "folder/../../Cargo.toml",
+            "./../Cargo.toml",
+            "/etc/passwd",
+        ] {
+            assert!(matches!(loader().resolve_path(path), Err(ThumborError::Forbidden(_))), "{}", path);
+        }
+        assert!(matches!(loader().resolve_path("sun.jpg\0.png"), Err(ThumborError::BadRequest(_))));
+
+        // The path is already decoded, escapes left in it are part of the file name
+        for path in ["%2e%2e/Cargo.toml", "..%2fCargo.toml", "%2fetc%2fpasswd"] {
+            assert!(matches!(loader().resolve_path(path), Err(ThumborError::SourceNotFound(_))), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_resolve_path_is_not_decoded_again() {
+        let root = std::env::temp_dir().join(format!("thumbor-file-loader-percent-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("100%25.jpg"), b"image").unwrap();
+
+        // `/unsafe/100%2525.jpg` reaches the loader as `100%25.jpg`
+        let loader = FileLoader::new(root.clone(), false);
+        assert_eq!(loader.resolve_path("100%25.jpg").unwrap(), root.canonicalize().unwrap().join("100%25.jpg"));
+        assert!(matches!(loader.resolve_path("100%.jpg"), Err(ThumborError::SourceNotFound(_))));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_path_symlinks() {
+        let root = std::env::temp_dir().join(format!("thumbor-file-loader-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("image.jpg"), b"image").unwrap();
+        std::os::unix::fs::symlink(root.join("image.jpg"), root.join("inside.jpg")).unwrap();
+        std::os::unix::fs::symlink(std::fs::canonicalize("./Cargo.toml").unwrap(), root.join("outside.jpg")).unwrap();
+
+        let strict = FileLoader::new(root.clone(), false);
+        assert!(strict.resolve_path("image.jpg").is_ok());
+        assert!(matches!(strict.resolve_path("inside.jpg"), Err(ThumborError::Forbidden(_))));
+
+        let following = FileLoader::new(root.clone(), true);
+        assert_eq!(following.resolve_path("inside.jpg").unwrap(), root.canonicalize().unwrap().join("image.jpg"));
+        assert!(matches!(following.resolve_path("outside.jpg"), Err(ThumborError::Forbidden(_))));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use std::time::Duration;
+
+use actix_web::http::header::HeaderMap as RequestHeaders;
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, CONTENT_TYPE, ETAG, LAST_MODIFIED};
+use reqwest::{Client, Response, StatusCode};
+
+use super::Loader;
+use crate::error::ThumborError;
+use crate::service::image::SourceImage;
+use crate::settings::Settings;
+
+/**
+ * Returns a client with the connection settings of the loader. The read timeout and the body
+ * limit are enforced by the loader while it streams the body.
+ */
+pub fn build_client(connect_timeout: Duration, max_redirects: usize, user_agent: &str) -> Result<Client, ThumborError> {
+    Client::builder()
+        .connect_timeout(connect_timeout)
+        .redirect(reqwest::redirect::Policy::limited(max_redirects))
+        .user_agent(user_agent)
+        .build()
+        .map_err(|error| ThumborError::Internal(error.to_string()))
+}
+
+fn request_error(url: &str, error: reqwest::Error) -> ThumborError {
+    if error.is_timeout() {
+        ThumborError::UpstreamTimeout(url.to_string())
+    } else if error.is_redirect() {
+        ThumborError::Upstream(format!("{} has too many redirects", url))
+    } else {
+        ThumborError::Upstream(error.to_string())
+    }
+}
+
+/**
+ * Loads images from `http://` and `https://` urls. With a default scheme, paths without
+ * scheme are loaded as `<scheme>://<path>` too, e.g. after the file loader did not find them.
+ */
+pub struct HttpLoader {
+    client: Client,
+    default_scheme: Option<String>,
+    // Longest wait for the headers, then for each chunk of the body
+    read_timeout: Duration,
+    // 0 for no limit
+    max_body_size: usize,
+    forwarded_header_names: Vec<HeaderName>,
+}
+
+impl HttpLoader {
+    pub fn new(client: Client, default_scheme: Option<String>, read_timeout: Duration, max_body_size: usize) -> Self {
+        HttpLoader { client, default_scheme, read_timeout, max_body_size, forwarded_header_names: vec![] }
+    }
+
+    /**
+     * The loader of the shared chain, so its client and connection pool serve every request.
+     * The headers of `http_loader_forward_headers` are copied from each request.
+     * Fails with `Internal` when the client cannot be built, e.g. for an invalid user agent.
+     */
+    pub fn from_settings(settings: &Settings) -> Result<Self, ThumborError> {
+        let client = build_client(
+            Duration::from_secs(settings.http_loader_connect_timeout),
+            settings.http_loader_max_redirects,
+            &settings.http_loader_user_agent,
+        )?;
+        let loader = HttpLoader::new(
+            client,
+            Some(settings.http_loader_default_scheme.clone()).filter(|scheme| !scheme.is_empty()),
+            Duration::from_secs(settings.http_loader_read_timeout),
+            settings.http_loader_max_body_size,
+        );
+        Ok(loader.forward_headers(&settings.http_loader_forward_headers))
+    }
+
+    /**
+     * Sends the request headers with these names to the origin, e.g. `Authorization`.
+     */
+    pub fn forward_headers(mut self, names: &[String]) -> Self {
+        self.forwarded_header_names.extend(names.iter().filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok()));
+        self
+    }
+
+    fn get_forwarded_headers(&self, request_headers: &RequestHeaders) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for name in &self.forwarded_header_names {
+            for value in request_headers.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        headers
+    }
+
+    /**
+     * Returns the url to request for the path, `None` when the loader does not handle it.
+     */
+    pub fn get_url(&self, path: &str) -> Option<String> {
+        if path.starts_with("http://") || path.starts_with("https://") {
+            return Some(path.to_string());
+        }
+        if path.contains("://") || path.starts_with("data:") {
+            return None;
+        }
+        self.default_scheme.as_ref().map(|scheme| format!("{}://{}", scheme, path))
+    }
+
+    async fn read_body(&self, url: &str, mut resp: Response) -> Result<Vec<u8>, ThumborError> {
+        let too_large = || ThumborError::TooLarge(format!("{} is larger than {} bytes", url, self.max_body_size));
+        if self.max_body_size > 0 && resp.content_length().is_some_and(|length| length > self.max_body_size as u64) {
+            return Err(too_large());
+        }
+
+        // The content length may be missing or wrong, so the limit is checked on every chunk too
+        let mut bytes = Vec::new();
+        loop {
+            let chunk = tokio::time::timeout(self.read_timeout, resp.chunk())
+                .await
+                .map_err(|_| ThumborError::UpstreamTimeout(url.to_string()))?
+                .map_err(|error| request_error(url, error))?;
+            match chunk {
+                Some(chunk) if self.max_body_size > 0 && bytes.len() + chunk.len() > self.max_body_size => {
+                    return Err(too_large());
+                }
+                Some(chunk) => bytes.extend_from_slice(&chunk),
+                None => return Ok(bytes),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Loader for HttpLoader {
+    fn can_load(&self, path: &str) -> bool {
+        self.get_url(path).is_some()
+    }
+
+    async fn load(&self, path: &str, request_headers: &RequestHeaders) -> Result<SourceImage, ThumborError> {
+        let url = self.get_url(path).ok_or_else(|| ThumborError::BadRequest(format!("Not an http url: {}", path)))?;
+        let request = self.client.get(&url).headers(self.get_forwarded_headers(request_headers)).send();
+        let resp = tokio::time::timeout(self.read_timeout, request)
+            .await
+            .map_err(|_| ThumborError::UpstreamTimeout(url.clone()))?
+            .map_err(|error| request_error(&url, error))?;
+
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Err(ThumborError::SourceNotFound(url));
+        }
+
+        if !resp.status().is_success() {
+            return Err(ThumborError::Upstream(format!("{} returned {}", url, resp.status())));
+        }
+
+        let header = |name: HeaderName| resp.headers().get(name).and_then(|value| value.to_str().ok()).map(|value| value.to_string());
+        let mime_type = header(CONTENT_TYPE).unwrap_or_else(|| "type/jpeg".to_string());
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED).and_then(|value| httpdate::parse_http_date(&value).ok());
+        let bytes = self.read_body(&url, resp).await?;
+
+        Ok(SourceImage { mime_type, bytes, last_modified, etag })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    fn loader(default_scheme: Option<String>, max_body_size: usize) -> HttpLoader {
+        let client = build_client(Duration::from_secs(1), 2, "thumbor-test").unwrap();
+        HttpLoader::new(client, default_scheme, Duration::from_secs(1), max_body_size)
+    }
+
+    #[test]
+    fn test_from_settings() {
+        let settings = Settings { http_loader_user_agent: "thumbor-test".to_string(), ..Default::default() };
+        assert!(HttpLoader::from_settings(&settings).is_ok());
+
+        let settings = Settings { http_loader_user_agent: "thumbor\ntest".to_string(), ..Default::default() };
+        assert!(matches!(HttpLoader::from_settings(&settings), Err(ThumborError::Internal(_))));
+    }
+
+    #[test]
+    fn test_get_url() {
+        let http_loader = loader(None, 0);
+        assert_eq!(http_loader.get_url("http://example.com/a.jpg"), Some("http://example.com/a.jpg".to_string()));
+        assert_eq!(http_loader.get_url("https://example.com/a.jpg"), Some("https://example.com/a.jpg".to_string()));
+        assert_eq!(http_loader.get_url("example.com/a.jpg"), None);
+        assert_eq!(http_loader.get_url("ftp://example.com/a.jpg"), None);
+
+        let http_loader = loader(Some("https".to_string()), 0);
+        assert_eq!(http_loader.get_url("example.com/a.jpg"), Some("https://example.com/a.jpg".to_string()));
+        assert_eq!(http_loader.get_url("data:image/png;base64,AAAA"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_load() {
+        let bytes = std::fs::read("./src/images/sun.jpg").unwrap();
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/sun.jpg")
+            .match_header("user-agent", "thumbor-test")
+            .with_header("content-type", "image/jpeg")
+            .with_header("etag", "\"abc\"")
+            .with_header("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+            .with_body(&bytes)
+            .create_async()
+            .await;
+
+        let source = loader(None, 0).load(&format!("{}/sun.jpg", server.url()), &RequestHeaders::new()).await.unwrap();
+        assert_eq!(source.mime_type, "image/jpeg");
+        assert_eq!(source.bytes, bytes);
+        assert_eq!(source.etag, Some("\"abc\"".to_string()));
+        assert_eq!(source.last_modified, httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").ok());
+    }
+
+    #[actix_web::test]
+    async fn test_load_errors() {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/missing.jpg").with_status(404).create_async().await;
+        server.mock("GET", "/broken.jpg").with_status(500).create_async().await;
+        let http_loader = loader(None, 0);
+
+        let missing = http_loader.load(&format!("{}/missing.jpg", server.url()), &RequestHeaders::new()).await;
+        assert!(matches!(missing, Err(ThumborError::SourceNotFound(_))));
+
+        let broken = http_loader.load(&format!("{}/broken.jpg", server.url()), &RequestHeaders::new()).await;
+        assert!(matches!(broken, Err(ThumborError::Upstream(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_load_too_large() {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/big.jpg").with_body(vec![0; 1000]).create_async().await;
+        server.mock("GET", "/chunked.jpg")
+            .with_chunked_body(|writer| writer.write_all(&[0; 1000]))
+            .create_async()
+            .await;
+
+        let big = loader(None, 100).load(&format!("{}/big.jpg", server.url()), &RequestHeaders::new()).await;
+        assert!(matches!(big, Err(ThumborError::TooLarge(_))));
+
+        let chunked = loader(None, 100).load(&format!("{}/chunked.jpg", server.url()), &RequestHeaders::new()).await;
+        assert!(matches!(chunked, Err(ThumborError::TooLarge(_))));
+
+        assert_eq!(loader(None, 1000).load(&format!("{}/big.jpg", server.url()), &RequestHeaders::new()).await.unwrap().bytes.len(), 1000);
+    }
+
+    #[actix_web::test]
+    async fn test_load_too_many_redirects() {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/loop.jpg")
+            .with_status(302)
+            .with_header("location", &format!("{}/loop.jpg", server.url()))
+            .expect_at_most(10)
+            .create_async()
+            .await;
+
+        let redirected = loader(None, 0).load(&format!("{}/loop.jpg", server.url()), &RequestHeaders::new()).await;
+        assert!(matches!(redirected, Err(ThumborError::Upstream(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_forward_headers() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server.mock("GET", "/private.jpg")
+            .match_header("authorization", "Bearer token")
+            .match_header("cookie", mockito::Matcher::Missing)
+            .create_async()
+            .await;
+
+        let mut request_headers = RequestHeaders::new();
+        request_headers.insert(reqwest::header::AUTHORIZATION, "Bearer token".parse().unwrap());
+        request_headers.insert(reqwest::header::COOKIE, "session=1".parse().unwrap());
+        let http_loader = loader(None, 0).forward_headers(&["Authorization".to_string()]);
+
+        assert!(http_loader.load(&format!("{}/private.jpg", server.url()), &request_headers).await.is_ok());
+        mock.assert_async().await;
+
+        // The loader is shared, the headers of a request are not sent with the next one
+        let public = server.mock("GET", "/public.jpg")
+            .match_header("authorization", mockito::Matcher::Missing)
+            .create_async()
+            .await;
+        assert!(http_loader.load(&format!("{}/public.jpg", server.url()), &RequestHeaders::new()).await.is_ok());
+        public.assert_async().await;
+    }
+}
@@ -0,0 +1,140 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use lazy_static::lazy_static;
+use tokio::sync::oneshot;
+
+use crate::error::ThumborError;
+use crate::settings::{Settings, CONF_S};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+lazy_static! {
+    static ref PROCESSING_POOL: ProcessingPool = ProcessingPool::new(
+        if CONF_S.processing_threads > 0 { CONF_S.processing_threads } else { num_cpus::get() },
+        CONF_S.processing_queue_size,
+    );
+}
+
+/**
+ * Fixed set of threads that run the CPU-bound image work (decode, resize, encode)
+ * away from the async workers. Jobs wait in a bounded queue, and are refused when it is full.
+ */
+pub struct ProcessingPool {
+    sender: SyncSender<Job>,
+}
+
+impl ProcessingPool {
+    pub fn new(threads: usize, queue_size: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("processing-{}", index))
+                .spawn(move || run_worker(receiver))
+                .expect("Start processing thread");
+        }
+
+        ProcessingPool { sender }
+    }
+
+    /**
+     * Queues the job and returns the receiver of its result.
+     * Fails with `ServiceUnavailable` when the queue is full.
+     */
+    pub fn submit<F, T>(&self, job: F) -> Result<oneshot::Receiver<T>, ThumborError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_sender, result_receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = result_sender.send(job());
+        });
+
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(result_receiver),
+            Err(TrySendError::Full(_)) => Err(ThumborError::ServiceUnavailable("Processing queue is full".to_string())),
+            Err(TrySendError::Disconnected(_)) => Err(ThumborError::Internal("Processing pool stopped".to_string())),
+        }
+    }
+
+    pub async fn run<F, T>(&self, job: F) -> Result<T, ThumborError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(job)?
+            .await
+            .map_err(|_| ThumborError::Internal("Image processing failed".to_string()))
+    }
+}
+
+fn run_worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    // Settings are thread local, so every processing thread loads its own copy
+    Settings::start();
+
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        // A panic drops the result sender, so the request fails but the thread keeps running
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+pub fn processing_pool() -> &'static ProcessingPool {
+    &PROCESSING_POOL
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    #[actix_web::test]
+    async fn test_run_job() {
+        let pool = ProcessingPool::new(2, 4);
+        assert_eq!(pool.run(|| 40 + 2).await, Ok(42));
+    }
+
+    #[actix_web::test]
+    async fn test_run_job_that_panics() {
+        let pool = ProcessingPool::new(1, 4);
+        let result: Result<i32, ThumborError> = pool.run(|| panic!("Bad image")).await;
+        assert_eq!(result, Err(ThumborError::Internal("Image processing failed".to_string())));
+
+        // The thread is still alive after the panic
+        assert_eq!(pool.run(|| 1).await, Ok(1));
+    }
+
+    #[actix_web::test]
+    async fn test_submit_when_queue_is_full() {
+        let pool = ProcessingPool::new(1, 1);
+        let (started_sender, started_receiver) = mpsc::channel();
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+
+        let running = pool.submit(move || {
+            started_sender.send(()).unwrap();
+            release_receiver.recv().unwrap();
+            1
+        }).unwrap();
+        started_receiver.recv().unwrap();
+
+        let queued = pool.submit(|| 2).unwrap();
+        assert_eq!(
+            pool.submit(|| 3).err(),
+            Some(ThumborError::ServiceUnavailable("Processing queue is full".to_string()))
+        );
+
+        release_sender.send(()).unwrap();
+        assert_eq!(running.await, Ok(1));
+        assert_eq!(queued.await, Ok(2));
+    }
+}
@@ -14,6 +14,33 @@ lazy_static! {
 pub struct Settings {
     pub debug: bool,
     pub secret_key: String,
+    pub no_upscale: bool,
+    pub processing_threads: usize,
+    pub processing_queue_size: usize,
+    pub auto_webp: bool,
+    pub auto_avif: bool,
+    pub quality: i32,
+    pub webp_quality: Option<i32>,
+    pub progressive_jpeg: bool,
+    pub background_color: String,
+    pub preserve_exif: bool,
+    pub preserve_icc: bool,
+    pub face_cascade_path: String,
+    pub profile_cascade_path: String,
+    pub eyes_cascade_path: String,
+    pub smart_detectors: Vec<String>,
+    pub detection_cache_size: usize,
+    pub detection_cache_path: String,
+    pub loaders: Vec<String>,
+    pub http_loader_default_scheme: String,
+    pub http_loader_connect_timeout: u64,
+    pub http_loader_read_timeout: u64,
+    pub http_loader_max_body_size: usize,
+    pub http_loader_max_redirects: usize,
+    pub http_loader_user_agent: String,
+    pub http_loader_forward_headers: Vec<String>,
+    pub file_loader_root_path: String,
+    pub file_loader_follow_symlinks: bool,
 }
 
 impl Settings {
@@ -23,6 +50,32 @@ impl Settings {
         let c = Config::builder()
             .set_default("debug", false)?
             .set_default("secret_key", "")?
+            .set_default("no_upscale", false)?
+            .set_default("processing_threads", 0)?
+            .set_default("processing_queue_size", 64)?
+            .set_default("auto_webp", false)?
+            .set_default("auto_avif", false)?
+            .set_default("quality", 80)?
+            .set_default("progressive_jpeg", true)?
+            .set_default("background_color", "ffffff")?
+            .set_default("preserve_exif", false)?
+            .set_default("preserve_icc", true)?
+            .set_default("face_cascade_path", "/usr/share/opencv4/haarcascades/haarcascade_frontalface_default.xml")?
+            .set_default("profile_cascade_path", "/usr/share/opencv4/haarcascades/haarcascade_profileface.xml")?
+            .set_default("eyes_cascade_path", "/usr/share/opencv4/haarcascades/haarcascade_eye.xml")?
+            .set_default("smart_detectors", vec!["face", "feature"])?
+            .set_default("detection_cache_size", 1000)?
+            .set_default("detection_cache_path", "")?
+            .set_default("loaders", vec!["data", "file", "http"])?
+            .set_default("http_loader_default_scheme", "")?
+            .set_default("http_loader_connect_timeout", 5)?
+            .set_default("http_loader_read_timeout", 20)?
+            .set_default("http_loader_max_body_size", 20 * 1024 * 1024)?
+            .set_default("http_loader_max_redirects", 5)?
+            .set_default("http_loader_user_agent", format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")))?
+            .set_default("http_loader_forward_headers", Vec::<String>::new())?
+            .set_default("file_loader_root_path", "./src/images")?
+            .set_default("file_loader_follow_symlinks", false)?
             .add_source(File::with_name("config/default.toml"));
 
         if !file_config_path.is_empty() {
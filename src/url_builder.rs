@@ -0,0 +1,240 @@
+use crate::security::{self, KeyError};
+use crate::url_parser;
+use crate::url_props::{Alignment, Dimension, FlipImage, ImageSize, UrlProps};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HorizontalAlignment {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerticalAlignment {
+    Top,
+    Middle,
+    Bottom,
+}
+
+/**
+ * Builds thumbnail urls in the same canonical order the server parses them.
+ *
+ * ```
+ * use thumbor_rust::url_builder::{UrlBuilder, HorizontalAlignment};
+ *
+ * let url = UrlBuilder::new("image.jpg")
+ *     .size(-300, 200)
+ *     .halign(HorizontalAlignment::Left)
+ *     .build_unsafe();
+ * assert_eq!(url, "/unsafe/-300x200/left/image.jpg");
+ * ```
+ */
+#[derive(Debug, Clone)]
+pub struct UrlBuilder {
+    image: String,
+    size: Option<(i32, i32)>,
+    flip: FlipImage,
+    halign: HorizontalAlignment,
+    valign: VerticalAlignment,
+    smart: bool,
+}
+
+impl UrlBuilder {
+    pub fn new(image: &str) -> Self {
+        UrlBuilder {
+            image: image.to_string(),
+            size: None,
+            flip: FlipImage { horizontal: false, vertical: false },
+            halign: HorizontalAlignment::Center,
+            valign: VerticalAlignment::Middle,
+            smart: false,
+        }
+    }
+
+    /**
+     * Sets the size of the thumbnail. A negative side flips the image on that axis,
+     * and zero keeps the aspect ratio from the other side. Calling it again replaces the size.
+     */
+    pub fn size(mut self, width: i32, height: i32) -> Self {
+        self.size = Some((width, height));
+        self
+    }
+
+    /**
+     * Flips the image horizontally, whatever the sign of the width.
+     */
+    pub fn flip_horizontal(mut self) -> Self {
+        self.flip.horizontal = true;
+        self
+    }
+
+    /**
+     * Flips the image vertically, whatever the sign of the height.
+     */
+    pub fn flip_vertical(mut self) -> Self {
+        self.flip.vertical = true;
+        self
+    }
+
+    pub fn halign(mut self, halign: HorizontalAlignment) -> Self {
+        self.halign = halign;
+        self
+    }
+
+    pub fn valign(mut self, valign: VerticalAlignment) -> Self {
+        self.valign = valign;
+        self
+    }
+
+    pub fn smart(mut self) -> Self {
+        self.smart = true;
+        self
+    }
+
+    fn url_props(&self) -> UrlProps {
+        let (width, height) = self.size.unwrap_or((0, 0));
+        // The sign of the size and the flip methods both flip the image, they do not cancel out
+        let flip = FlipImage {
+            horizontal: self.flip.horizontal || width < 0,
+            vertical: self.flip.vertical || height < 0,
+        };
+        let (width, height) = (width.abs(), height.abs());
+        let has_size = self.size.is_some() || flip.horizontal || flip.vertical;
+
+        UrlProps {
+            debug: false,
+            meta: false,
+            trim: None,
+            crop: None,
+            fit_in: None,
+            size: if has_size {
+                Some(ImageSize { width: Dimension::Pixels(width), height: Dimension::Pixels(height) })
+            } else {
+                None
+            },
+            width,
+            height,
+            filename: self.image.clone(),
+            alignment: Alignment {
+                halign: match self.halign {
+                    HorizontalAlignment::Left => "left",
+                    HorizontalAlignment::Center => "center",
+                    HorizontalAlignment::Right => "right",
+                }.to_string(),
+                valign: match self.valign {
+                    VerticalAlignment::Top => "top",
+                    VerticalAlignment::Middle => "middle",
+                    VerticalAlignment::Bottom => "bottom",
+                }.to_string(),
+                smart: self.smart,
+                explicit_halign: self.halign != HorizontalAlignment::Center,
+                explicit_valign: self.valign != VerticalAlignment::Middle,
+            },
+            flip,
+            filters: vec![],
+        }
+    }
+
+    /**
+     * Returns the path without the key, which is the part that is signed.
+     */
+    pub fn path(&self) -> String {
+        url_parser::serialize(&self.url_props())
+    }
+
+    pub fn build_unsafe(&self) -> String {
+        format!("/unsafe/{}", self.path())
+    }
+
+    /**
+     * Returns the url signed with the secret key, as the server validates it.
+     */
+    pub fn build_signed(&self, secret_key: &str) -> Result<String, KeyError> {
+        let path = self.path();
+        Ok(format!("/{}/{}", security::sign(secret_key, &path)?, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_build_unsafe() {
+        assert_eq!(UrlBuilder::new("big.jpg").build_unsafe(), "/unsafe/big.jpg");
+        assert_eq!(UrlBuilder::new("big.jpg").size(300, 200).build_unsafe(), "/unsafe/300x200/big.jpg");
+        assert_eq!(
+            UrlBuilder::new("http://picsum.photo/500/500.jpg").size(-300, -200).smart().build_unsafe(),
+            "/unsafe/-300x-200/smart/http://picsum.photo/500/500.jpg"
+        );
+        assert_eq!(
+            UrlBuilder::new("big.jpg")
+                .size(300, 0)
+                .flip_vertical()
+                .halign(HorizontalAlignment::Right)
+                .valign(VerticalAlignment::Bottom)
+                .build_unsafe(),
+            "/unsafe/300x-0/right/bottom/big.jpg"
+        );
+        assert_eq!(UrlBuilder::new("big.jpg").flip_horizontal().build_unsafe(), "/unsafe/-0x0/big.jpg");
+    }
+
+    #[test]
+    fn test_build_unsafe_with_repeated_and_combined_flips() {
+        assert_eq!(UrlBuilder::new("big.jpg").size(-300, 200).size(-300, 200).build_unsafe(), "/unsafe/-300x200/big.jpg");
+        assert_eq!(UrlBuilder::new("big.jpg").size(-300, 200).size(300, -200).build_unsafe(), "/unsafe/300x-200/big.jpg");
+        assert_eq!(UrlBuilder::new("big.jpg").flip_horizontal().size(-300, 200).build_unsafe(), "/unsafe/-300x200/big.jpg");
+        assert_eq!(UrlBuilder::new("big.jpg").size(-300, 200).flip_horizontal().build_unsafe(), "/unsafe/-300x200/big.jpg");
+        assert_eq!(
+            UrlBuilder::new("big.jpg").flip_vertical().flip_vertical().size(300, -200).build_unsafe(),
+            "/unsafe/300x-200/big.jpg"
+        );
+        assert_eq!(UrlBuilder::new("big.jpg").flip_horizontal().size(300, 200).build_unsafe(), "/unsafe/-300x200/big.jpg");
+    }
+
+    #[test]
+    fn test_build_signed() {
+        assert_eq!(
+            UrlBuilder::new("big.jpg").size(50, 50).build_signed("MY_KEY").unwrap(),
+            "/sMxTvxyS2uudMVBgjPv_YfTFe3E=/50x50/big.jpg"
+        );
+        assert_eq!(
+            UrlBuilder::new("http://picsum.photo/500/500.jpg").size(300, 200).smart().build_signed("MY_KEY").unwrap(),
+            "/FJd9jVRAhh4rucHcwAlqAJyHyd8=/300x200/smart/http://picsum.photo/500/500.jpg"
+        );
+    }
+
+    #[test]
+    fn test_signed_url_is_valid_key() {
+        Settings {
+            secret_key: "MY_KEY".to_string(),
+            ..Default::default()
+        }.make_current();
+
+        let url = UrlBuilder::new("big.jpg").size(-670, 390).valign(VerticalAlignment::Top).build_signed("MY_KEY").unwrap();
+        assert!(security::is_valid_key(url));
+    }
+
+    #[test]
+    fn test_round_trip_through_url_parser() {
+        let builders = vec![
+            UrlBuilder::new("big.jpg"),
+            UrlBuilder::new("big.jpg").size(600, 300),
+            UrlBuilder::new("big.jpg").size(-602, 303).smart(),
+            UrlBuilder::new("big.jpg").size(602, -303).halign(HorizontalAlignment::Left),
+            UrlBuilder::new("big.jpg").size(0, 100).valign(VerticalAlignment::Top).smart(),
+            UrlBuilder::new("http://picsum.photo/500/500.jpg")
+                .size(300, 200)
+                .halign(HorizontalAlignment::Right)
+                .valign(VerticalAlignment::Bottom),
+        ];
+
+        for builder in builders {
+            let url_props = url_parser::parse(&builder.path()).unwrap();
+            assert_eq!(url_props, builder.url_props());
+            assert_eq!(url_parser::serialize(&url_props), builder.path());
+        }
+    }
+}
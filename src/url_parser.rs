@@ -0,0 +1,501 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use thiserror::Error;
+
+use crate::url_props::{
+    Alignment, CropBox, Dimension, FilterCall, FitIn, FlipImage, ImageSize, Trim, TrimCorner, UrlProps,
+};
+
+lazy_static! {
+    // Same segment order as Thumbor:
+    // debug/meta/trim/AxB:CxD/fit-in/-Wx-H/halign/valign/smart/filters:.../image
+    static ref URL_PATTERN: Regex = Regex::new(concat!(
+        r"^(?:(?P<debug>debug)/)?",
+        r"(?:(?P<meta>meta)/)?",
+        r"(?:(?P<trim>trim)(?::(?P<trim_corner>top-left|bottom-right))?(?::(?P<trim_tolerance>\d+))?/)?",
+        r"(?:(?P<crop_left>\d+)x(?P<crop_top>\d+):(?P<crop_right>\d+)x(?P<crop_bottom>\d+)/)?",
+        r"(?:(?P<fit_in>(?:adaptive-)?(?:full-)?fit-in)/)?",
+        r"(?:(?P<size>(?P<horizontal_flip>-)?(?P<width>\d+|orig)?x(?P<vertical_flip>-)?(?P<height>\d+|orig)?)/)?",
+        r"(?:(?P<halign>left|right|center)/)?",
+        r"(?:(?P<valign>top|bottom|middle)/)?",
+        r"(?:(?P<smart>smart)/)?",
+        r"(?:filters:(?P<filters>.+?\))/)?",
+        r"(?P<image>.+)$",
+    )).unwrap();
+    static ref FOCAL_PATTERN: Regex = Regex::new(r"^(\d+)x(\d+):(\d+)x(\d+)$").unwrap();
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("Missing image in url: {0}")]
+    MissingImage(String),
+    #[error("Invalid number: {0}")]
+    InvalidNumber(String),
+    #[error("Invalid filter: {0}")]
+    InvalidFilter(String),
+    #[error("Invalid crop box: {0}")]
+    InvalidCrop(String),
+}
+
+fn parse_number(value: &str) -> Result<i32, ParseError> {
+    value.parse::<i32>().map_err(|_| ParseError::InvalidNumber(value.to_string()))
+}
+
+fn parse_dimension(value: Option<&str>) -> Result<Dimension, ParseError> {
+    match value {
+        None => Ok(Dimension::Auto),
+        Some("orig") => Ok(Dimension::Orig),
+        Some(pixels) => Ok(Dimension::Pixels(parse_number(pixels)?)),
+    }
+}
+
+fn capture<'a>(captures: &Captures<'a>, name: &str) -> Option<&'a str> {
+    captures.name(name).map(|value| value.as_str())
+}
+
+/**
+ * Splits the arguments of a filter by the commas that are not inside parentheses.
+ */
+fn split_filter_args(args: &str) -> Vec<String> {
+    if args.is_empty() {
+        return vec![];
+    }
+
+    let mut result = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in args.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                result.push(args[start..index].to_string());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(args[start..].to_string());
+    result
+}
+
+/**
+ * Parses the `AxB:CxD` rectangle of the `focal()` filter. Returns `None` when it is empty.
+ */
+pub fn parse_focal_rect(value: &str) -> Option<CropBox> {
+    let captures = FOCAL_PATTERN.captures(value.trim())?;
+    let number = |index: usize| captures.get(index)?.as_str().parse::<i32>().ok();
+    let crop_box = CropBox { left: number(1)?, top: number(2)?, right: number(3)?, bottom: number(4)? };
+
+    Some(crop_box).filter(|crop_box| crop_box.right > crop_box.left && crop_box.bottom > crop_box.top)
+}
+
+/**
+ * Parses `name(args):name2(args)` into a list of filter calls, in URL order.
+ */
+pub fn parse_filters(filters: &str) -> Result<Vec<FilterCall>, ParseError> {
+    let mut calls = vec![];
+    let mut rest = filters;
+
+    while !rest.is_empty() {
+        let current = rest;
+        let invalid = || ParseError::InvalidFilter(current.to_string());
+
+        let open = rest.find('(').ok_or_else(invalid)?;
+        let name = &rest[..open];
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(invalid());
+        }
+
+        let mut depth = 0;
+        let mut close = None;
+        for (index, c) in rest[open..].char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = Some(open + index);
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let close = close.ok_or_else(invalid)?;
+
+        calls.push(FilterCall {
+            name: name.to_string(),
+            args: split_filter_args(&rest[open + 1..close]),
+        });
+
+        rest = &rest[close + 1..];
+        if let Some(next) = rest.strip_prefix(':') {
+            if next.is_empty() {
+                return Err(invalid());
+            }
+            rest = next;
+        } else if !rest.is_empty() {
+            return Err(invalid());
+        }
+    }
+
+    Ok(calls)
+}
+
+/**
+ * Parses the part of a Thumbor url after the key (`unsafe` or the hmac) into `UrlProps`.
+ *
+ * Example: `fit-in/-300x200/left/top/smart/filters:quality(80)/image.jpg`
+ */
+pub fn parse(path: &str) -> Result<UrlProps, ParseError> {
+    let path = path.trim_start_matches('/');
+    let captures = URL_PATTERN
+        .captures(path)
+        .ok_or_else(|| ParseError::MissingImage(path.to_string()))?;
+
+    let trim = match capture(&captures, "trim") {
+        Some(_) => Some(Trim {
+            corner: capture(&captures, "trim_corner").map(|corner| match corner {
+                "bottom-right" => TrimCorner::BottomRight,
+                _ => TrimCorner::TopLeft,
+            }),
+            tolerance: capture(&captures, "trim_tolerance").map(parse_number).transpose()?,
+        }),
+        None => None,
+    };
+
+    let crop = match capture(&captures, "crop_left") {
+        Some(left) => Some(CropBox {
+            left: parse_number(left)?,
+            top: parse_number(capture(&captures, "crop_top").unwrap_or_default())?,
+            right: parse_number(capture(&captures, "crop_right").unwrap_or_default())?,
+            bottom: parse_number(capture(&captures, "crop_bottom").unwrap_or_default())?,
+        }),
+        None => None,
+    };
+    if let Some(crop_box) = crop.filter(|crop_box| crop_box.right <= crop_box.left || crop_box.bottom <= crop_box.top) {
+        let crop_box = format!("{}x{}:{}x{}", crop_box.left, crop_box.top, crop_box.right, crop_box.bottom);
+        return Err(ParseError::InvalidCrop(crop_box));
+    }
+
+    let fit_in = capture(&captures, "fit_in").map(|fit_in| match fit_in {
+        "adaptive-full-fit-in" => FitIn::AdaptiveFullFitIn,
+        "adaptive-fit-in" => FitIn::AdaptiveFitIn,
+        "full-fit-in" => FitIn::FullFitIn,
+        _ => FitIn::FitIn,
+    });
+
+    let size = match capture(&captures, "size") {
+        Some(_) => Some(ImageSize {
+            width: parse_dimension(capture(&captures, "width"))?,
+            height: parse_dimension(capture(&captures, "height"))?,
+        }),
+        None => None,
+    };
+
+    let filters = match capture(&captures, "filters") {
+        Some(filters) => parse_filters(filters)?,
+        None => vec![],
+    };
+
+    Ok(UrlProps {
+        debug: capture(&captures, "debug").is_some(),
+        meta: capture(&captures, "meta").is_some(),
+        trim,
+        crop,
+        fit_in,
+        size,
+        width: size.map(|size| size.width.pixels()).unwrap_or(0),
+        height: size.map(|size| size.height.pixels()).unwrap_or(0),
+        filename: capture(&captures, "image").unwrap_or_default().to_string(),
+        alignment: Alignment {
+            halign: capture(&captures, "halign").unwrap_or("center").to_string(),
+            valign: capture(&captures, "valign").unwrap_or("middle").to_string(),
+            smart: capture(&captures, "smart").is_some(),
+            explicit_halign: capture(&captures, "halign").is_some(),
+            explicit_valign: capture(&captures, "valign").is_some(),
+        },
+        flip: FlipImage {
+            horizontal: capture(&captures, "horizontal_flip").is_some(),
+            vertical: capture(&captures, "vertical_flip").is_some(),
+        },
+        filters,
+    })
+}
+
+fn serialize_dimension(dimension: Dimension) -> String {
+    match dimension {
+        Dimension::Auto => "".to_string(),
+        Dimension::Orig => "orig".to_string(),
+        Dimension::Pixels(pixels) => pixels.to_string(),
+    }
+}
+
+/**
+ * Builds the url path (without the key) back from `UrlProps`.
+ *
+ * Default alignments (`center` and `middle`) are omitted unless they were written in the url,
+ * so every parsed url round-trips byte-for-byte.
+ */
+pub fn serialize(url_props: &UrlProps) -> String {
+    let mut segments: Vec<String> = vec![];
+
+    if url_props.debug {
+        segments.push("debug".to_string());
+    }
+
+    if url_props.meta {
+        segments.push("meta".to_string());
+    }
+
+    if let Some(trim) = &url_props.trim {
+        let mut segment = "trim".to_string();
+        match trim.corner {
+            Some(TrimCorner::TopLeft) => segment.push_str(":top-left"),
+            Some(TrimCorner::BottomRight) => segment.push_str(":bottom-right"),
+            None => {}
+        }
+        if let Some(tolerance) = trim.tolerance {
+            segment.push_str(&format!(":{}", tolerance));
+        }
+        segments.push(segment);
+    }
+
+    if let Some(crop) = url_props.crop {
+        segments.push(format!("{}x{}:{}x{}", crop.left, crop.top, crop.right, crop.bottom));
+    }
+
+    if let Some(fit_in) = url_props.fit_in {
+        segments.push(match fit_in {
+            FitIn::FitIn => "fit-in",
+            FitIn::AdaptiveFitIn => "adaptive-fit-in",
+            FitIn::FullFitIn => "full-fit-in",
+            FitIn::AdaptiveFullFitIn => "adaptive-full-fit-in",
+        }.to_string());
+    }
+
+    if let Some(size) = url_props.size {
+        segments.push(format!(
+            "{}{}x{}{}",
+            if url_props.flip.horizontal { "-" } else { "" },
+            serialize_dimension(size.width),
+            if url_props.flip.vertical { "-" } else { "" },
+            serialize_dimension(size.height),
+        ));
+    }
+
+    if url_props.alignment.explicit_halign || url_props.alignment.halign != "center" {
+        segments.push(url_props.alignment.halign.clone());
+    }
+
+    if url_props.alignment.explicit_valign || url_props.alignment.valign != "middle" {
+        segments.push(url_props.alignment.valign.clone());
+    }
+
+    if url_props.alignment.smart {
+        segments.push("smart".to_string());
+    }
+
+    if !url_props.filters.is_empty() {
+        let filters: Vec<String> = url_props.filters
+            .iter()
+            .map(|filter| format!("{}({})", filter.name, filter.args.join(",")))
+            .collect();
+        segments.push(format!("filters:{}", filters.join(":")));
+    }
+
+    segments.push(url_props.filename.clone());
+    segments.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
+
+    #[test]
+    fn test_parse_only_image() {
+        let url_props = parse("image.jpg").unwrap();
+
+        assert!(!url_props.debug);
+        assert!(!url_props.meta);
+        assert_eq!(url_props.trim, None);
+        assert_eq!(url_props.crop, None);
+        assert_eq!(url_props.fit_in, None);
+        assert_eq!(url_props.size, None);
+        assert_eq!(url_props.width, 0);
+        assert_eq!(url_props.height, 0);
+        assert_eq!(url_props.filename, "image.jpg");
+        assert_eq!(url_props.alignment.halign, "center");
+        assert_eq!(url_props.alignment.valign, "middle");
+        assert!(!url_props.alignment.smart);
+        assert!(url_props.filters.is_empty());
+    }
+
+    #[test]
+    fn test_parse_size_and_alignment() {
+        let url_props = parse("/300x200/left/top/smart/image.jpg").unwrap();
+
+        assert_eq!(url_props.width, 300);
+        assert_eq!(url_props.height, 200);
+        assert_eq!(url_props.alignment.halign, "left");
+        assert_eq!(url_props.alignment.valign, "top");
+        assert!(url_props.alignment.smart);
+        assert!(!url_props.flip.horizontal);
+        assert!(!url_props.flip.vertical);
+    }
+
+    #[test]
+    fn test_parse_default_alignment() {
+        let url_props = parse("600x300/image.jpg").unwrap();
+
+        assert_eq!(url_props.width, 600);
+        assert_eq!(url_props.height, 300);
+        assert_eq!(url_props.filename, "image.jpg");
+        assert_eq!(url_props.alignment.halign, "center");
+        assert_eq!(url_props.alignment.valign, "middle");
+        assert!(!url_props.alignment.explicit_halign);
+        assert!(!url_props.alignment.explicit_valign);
+        assert!(!url_props.alignment.smart);
+        assert!(!url_props.flip.horizontal);
+        assert!(!url_props.flip.vertical);
+    }
+
+    #[test]
+    fn test_parse_smart_with_alignment() {
+        let url_props = parse("602x303/smart/image.jpg").unwrap();
+        assert_eq!(url_props.alignment.halign, "center");
+        assert_eq!(url_props.alignment.valign, "middle");
+        assert!(url_props.alignment.smart);
+
+        // The alignment is kept for when the detection finds nothing
+        for (path, halign, valign) in [
+            ("602x303/left/smart/image.jpg", "left", "middle"),
+            ("602x303/top/smart/image.jpg", "center", "top"),
+            ("602x303/right/bottom/smart/image.jpg", "right", "bottom"),
+        ] {
+            let url_props = parse(path).unwrap();
+            assert_eq!(url_props.alignment.halign, halign, "{}", path);
+            assert_eq!(url_props.alignment.valign, valign, "{}", path);
+            assert!(url_props.alignment.smart, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_parse_flips_and_dimensions() {
+        let url_props = parse("-x-orig/image.jpg").unwrap();
+
+        assert_eq!(url_props.size, Some(ImageSize { width: Dimension::Auto, height: Dimension::Orig }));
+        assert_eq!(url_props.width, 0);
+        assert_eq!(url_props.height, 0);
+        assert!(url_props.flip.horizontal);
+        assert!(url_props.flip.vertical);
+    }
+
+    #[test]
+    fn test_parse_all_segments() {
+        let url_props = parse(
+            "debug/meta/trim:bottom-right:10/10x20:300x400/adaptive-full-fit-in/-300x-200/right/bottom/smart/filters:quality(80):focal(1x2:3x4)/http://example.com/image.jpg"
+        ).unwrap();
+
+        assert!(url_props.debug);
+        assert!(url_props.meta);
+        assert_eq!(url_props.trim, Some(Trim { corner: Some(TrimCorner::BottomRight), tolerance: Some(10) }));
+        assert_eq!(url_props.crop, Some(CropBox { left: 10, top: 20, right: 300, bottom: 400 }));
+        assert_eq!(url_props.fit_in, Some(FitIn::AdaptiveFullFitIn));
+        assert_eq!(url_props.width, 300);
+        assert_eq!(url_props.height, 200);
+        assert!(url_props.flip.horizontal);
+        assert!(url_props.flip.vertical);
+        assert_eq!(url_props.alignment.halign, "right");
+        assert_eq!(url_props.alignment.valign, "bottom");
+        assert!(url_props.alignment.smart);
+        assert_eq!(url_props.filters, vec![
+            FilterCall { name: "quality".to_string(), args: vec!["80".to_string()] },
+            FilterCall { name: "focal".to_string(), args: vec!["1x2:3x4".to_string()] },
+        ]);
+        assert_eq!(url_props.filename, "http://example.com/image.jpg");
+    }
+
+    #[test]
+    fn test_parse_trim_without_options() {
+        let url_props = parse("trim/image.jpg").unwrap();
+        assert_eq!(url_props.trim, Some(Trim { corner: None, tolerance: None }));
+    }
+
+    #[test]
+    fn test_parse_fit_in_modes() {
+        assert_eq!(parse("fit-in/image.jpg").unwrap().fit_in, Some(FitIn::FitIn));
+        assert_eq!(parse("adaptive-fit-in/image.jpg").unwrap().fit_in, Some(FitIn::AdaptiveFitIn));
+        assert_eq!(parse("full-fit-in/image.jpg").unwrap().fit_in, Some(FitIn::FullFitIn));
+    }
+
+    #[test]
+    fn test_parse_invalid_crop() {
+        assert_eq!(parse("300x0:100x100/image.jpg"), Err(ParseError::InvalidCrop("300x0:100x100".to_string())));
+        assert_eq!(parse("0x100:100x100/image.jpg"), Err(ParseError::InvalidCrop("0x100:100x100".to_string())));
+        assert!(parse("0x0:1x1/image.jpg").is_ok());
+    }
+
+    #[test]
+    fn test_parse_invalid_number() {
+        assert_eq!(
+            parse("99999999999x10/image.jpg"),
+            Err(ParseError::InvalidNumber("99999999999".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_without_image() {
+        assert_eq!(parse(""), Err(ParseError::MissingImage("".to_string())));
+    }
+
+    #[test]
+    fn test_parse_filters() {
+        assert_eq!(parse_filters("no_upscale():fill(red,1)").unwrap(), vec![
+            FilterCall { name: "no_upscale".to_string(), args: vec![] },
+            FilterCall { name: "fill".to_string(), args: vec!["red".to_string(), "1".to_string()] },
+        ]);
+        assert_eq!(
+            parse_filters("quality(80):"),
+            Err(ParseError::InvalidFilter("quality(80):".to_string()))
+        );
+        assert_eq!(parse_filters("quality"), Err(ParseError::InvalidFilter("quality".to_string())));
+        assert_eq!(parse_filters("quality(80"), Err(ParseError::InvalidFilter("quality(80".to_string())));
+    }
+
+    #[test]
+    fn test_parse_focal_rect() {
+        assert_eq!(parse_focal_rect("10x20:110x220"), Some(CropBox { left: 10, top: 20, right: 110, bottom: 220 }));
+        assert_eq!(parse_focal_rect("110x20:10x220"), None);
+        assert_eq!(parse_focal_rect("10x20"), None);
+        assert_eq!(parse_focal_rect("-10x20:110x220"), None);
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let paths = vec![
+            "image.jpg",
+            "300x200/image.jpg",
+            "0x0/image.jpg",
+            "x300/image.jpg",
+            "-origx-0/image.jpg",
+            "trim/image.jpg",
+            "trim:top-left/image.jpg",
+            "trim:20/image.jpg",
+            "meta/trim:bottom-right:10/10x20:300x400/fit-in/300x200/left/top/smart/image.jpg",
+            "debug/300x200/smart/filters:detectors(face,feature)/image.jpg",
+            "adaptive-fit-in/-300x-200/bottom/smart/filters:quality(80):no_upscale()/http://example.com/a.jpg?x=1",
+            "full-fit-in/300x200/right/filters:focal(1x2:3x4):fill(red,1)/image.png",
+            "300x200/center/middle/image.jpg",
+            "300x200/center/image.jpg",
+            "300x200/middle/smart/image.jpg",
+            "fit-in/left/middle/image.jpg",
+        ];
+
+        for path in paths {
+            assert_eq!(serialize(&parse(path).unwrap()), path);
+        }
+    }
+}
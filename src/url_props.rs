@@ -1,197 +1,159 @@
-use serde::Deserialize;
+use crate::settings::conf;
 
-#[derive(Deserialize)]
-pub struct UrlPropsController {
-    pub key: String,
-    pub width: i32,
-    pub height: i32,
-    pub smart: String,
-    pub halign: String,
-    pub valign: String,
-    pub filename: String,
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Alignment {
     pub halign: String,
     pub valign: String,
     pub smart: bool,
+    // Whether the segments are in the url, even with the default `center` and `middle`,
+    // so the url is serialized back as it was written
+    pub explicit_halign: bool,
+    pub explicit_valign: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FlipImage {
     pub horizontal: bool,
     pub vertical: bool,
 }
 
-#[derive(Debug)]
+/**
+ * Which corner pixel is used as the reference colour when trimming borders.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrimCorner {
+    TopLeft,
+    BottomRight,
+}
+
+/**
+ * The `trim[:top-left|:bottom-right][:tolerance]` segment.
+ * Fields are `None` when they are omitted in the URL.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trim {
+    pub corner: Option<TrimCorner>,
+    pub tolerance: Option<i32>,
+}
+
+/**
+ * Manual crop box `/AxB:CxD/`, where (A, B) is the top left and (C, D) the bottom right point.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropBox {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitIn {
+    FitIn,
+    AdaptiveFitIn,
+    FullFitIn,
+    AdaptiveFullFitIn,
+}
+
+/**
+ * One side of the size segment as written in the URL.
+ * `Auto` is an empty value (`x300`), `Orig` is the `orig` keyword.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dimension {
+    Auto,
+    Orig,
+    Pixels(i32),
+}
+
+impl Dimension {
+    pub fn pixels(&self) -> i32 {
+        match self {
+            Dimension::Pixels(pixels) => *pixels,
+            Dimension::Auto | Dimension::Orig => 0,
+        }
+    }
+
+    /**
+     * Returns the pixels of this side, where `orig` is the original side of the image.
+     */
+    pub fn resolve(&self, original: i32) -> i32 {
+        match self {
+            Dimension::Orig => original,
+            _ => self.pixels(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageSize {
+    pub width: Dimension,
+    pub height: Dimension,
+}
+
+/**
+ * A filter call like `quality(80)`. Arguments are kept as written in the URL.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterCall {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UrlProps {
+    pub debug: bool,
+    pub meta: bool,
+    pub trim: Option<Trim>,
+    pub crop: Option<CropBox>,
+    pub fit_in: Option<FitIn>,
+    pub size: Option<ImageSize>,
     pub width: i32,
     pub height: i32,
     pub filename: String,
     pub alignment: Alignment,
     pub flip: FlipImage,
+    pub filters: Vec<FilterCall>,
 }
 
-pub fn build_url_props(uri: UrlPropsController) -> UrlProps {
-    UrlProps {
-        width: i32::abs(uri.width),
-        height: i32::abs(uri.height),
-        filename: uri.filename,
-        alignment: Alignment {
-            halign: if uri.halign.is_empty() {
-                "center".to_string()
-            }  else {
-                uri.halign.replace('/', "")
-            },
-            valign:  if uri.valign.is_empty() {
-                "middle".to_string()
-            } else {
-                uri.valign.replace('/', "")
-            },
-            smart: uri.smart.replace('/', "") == "smart" && (uri.halign.is_empty() && uri.valign.is_empty()),
-        },
-        flip: FlipImage {
-            horizontal: uri.width < 0,
-            vertical: uri.height < 0,
-        },
+impl UrlProps {
+    pub fn has_filter(&self, name: &str) -> bool {
+        self.filters.iter().any(|filter| filter.name == name)
+    }
+
+    /**
+     * Images are not resized above their original size when the `no_upscale` setting
+     * is enabled or the url has the `no_upscale()` filter.
+     */
+    pub fn allow_upscale(&self) -> bool {
+        !conf().no_upscale && !self.has_filter("no_upscale")
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use pretty_assertions::assert_eq; // crate for test-only use. Cannot be used in non-test code.
-
-    #[test]
-    fn test_build_url_props_with_detault_props() {
-        let url_props = build_url_props(UrlPropsController {
-            key: "unsafe".to_string(),
-            width: 100,
-            height: 200,
-            smart: "".to_string(),
-            halign: "".to_string(),
-            valign: "".to_string(),
-            filename: "image.jpg".to_string(),
-        });
-
-        assert_eq!(url_props.width, 100);
-        assert_eq!(url_props.height, 200);
-        assert_eq!(url_props.filename, "image.jpg");
-        assert_eq!(url_props.alignment.halign, "center");
-        assert_eq!(url_props.alignment.valign, "middle");
-        assert_eq!(url_props.alignment.smart, false);
-        assert_eq!(url_props.flip.horizontal, false);
-        assert_eq!(url_props.flip.vertical, false);
-    }
-
-    #[test]
-    fn test_build_url_props_with_detault_props_and_other_sizes() {
-        let url_props = build_url_props(UrlPropsController {
-            key: "unsafe".to_string(),
-            width: 600,
-            height: 300,
-            smart: "".to_string(),
-            halign: "".to_string(),
-            valign: "".to_string(),
-            filename: "image.jpg".to_string(),
-        });
-
-        assert_eq!(url_props.width, 600);
-        assert_eq!(url_props.height, 300);
-        assert_eq!(url_props.filename, "image.jpg");
-        assert_eq!(url_props.alignment.halign, "center");
-        assert_eq!(url_props.alignment.valign, "middle");
-        assert_eq!(url_props.alignment.smart, false);
-        assert_eq!(url_props.flip.horizontal, false);
-        assert_eq!(url_props.flip.vertical, false);
-    }
-
-    #[test]
-    fn test_build_url_props_with_smart() {
-        let url_props = build_url_props(UrlPropsController {
-            key: "unsafe".to_string(),
-            width: 602,
-            height: 303,
-            smart: "/smart".to_string(),
-            halign: "".to_string(),
-            valign: "".to_string(),
-            filename: "image.jpg".to_string(),
-        });
-
-        assert_eq!(url_props.width, 602);
-        assert_eq!(url_props.height, 303);
-        assert_eq!(url_props.filename, "image.jpg");
-        assert_eq!(url_props.alignment.halign, "center");
-        assert_eq!(url_props.alignment.valign, "middle");
-        assert_eq!(url_props.alignment.smart, true);
-        assert_eq!(url_props.flip.horizontal, false);
-        assert_eq!(url_props.flip.vertical, false);
-    }
+    use crate::settings::Settings;
+    use crate::url_parser;
 
     #[test]
-    fn test_build_url_props_with_smart_as_false_when_has_halign() {
-        let url_props = build_url_props(UrlPropsController {
-            key: "unsafe".to_string(),
-            width: 602,
-            height: 303,
-            smart: "/smart".to_string(),
-            halign: "/left".to_string(),
-            valign: "".to_string(),
-            filename: "image.jpg".to_string(),
-        });
-
-        assert_eq!(url_props.width, 602);
-        assert_eq!(url_props.height, 303);
-        assert_eq!(url_props.filename, "image.jpg");
-        assert_eq!(url_props.alignment.halign, "left");
-        assert_eq!(url_props.alignment.valign, "middle");
-        assert_eq!(url_props.alignment.smart, false);
-        assert_eq!(url_props.flip.horizontal, false);
-        assert_eq!(url_props.flip.vertical, false);
+    fn test_allow_upscale() {
+        Settings {
+            no_upscale: false,
+            ..Default::default()
+        }.make_current();
+
+        let mut url_props = url_parser::parse("600x300/image.jpg").unwrap();
+        assert!(url_props.allow_upscale());
+
+        url_props.filters.push(FilterCall { name: "no_upscale".to_string(), args: vec![] });
+        assert!(!url_props.allow_upscale());
+
+        url_props.filters.clear();
+        Settings {
+            no_upscale: true,
+            ..Default::default()
+        }.make_current();
+        assert!(!url_props.allow_upscale());
     }
-
-    #[test]
-    fn test_build_url_props_with_smart_as_false_when_has_valign() {
-        let url_props = build_url_props(UrlPropsController {
-            key: "unsafe".to_string(),
-            width: 602,
-            height: 303,
-            smart: "/smart".to_string(),
-            halign: "".to_string(),
-            valign: "/top".to_string(),
-            filename: "image.jpg".to_string(),
-        });
-
-        assert_eq!(url_props.width, 602);
-        assert_eq!(url_props.height, 303);
-        assert_eq!(url_props.filename, "image.jpg");
-        assert_eq!(url_props.alignment.halign, "center");
-        assert_eq!(url_props.alignment.valign, "top");
-        assert_eq!(url_props.alignment.smart, false);
-        assert_eq!(url_props.flip.horizontal, false);
-        assert_eq!(url_props.flip.vertical, false);
-    }
-
-    #[test]
-    fn test_build_url_props_with_smart_as_false_when_has_halign_and_valign() {
-        let url_props = build_url_props(UrlPropsController {
-            key: "unsafe".to_string(),
-            width: 602,
-            height: 303,
-            smart: "/smart".to_string(),
-            halign: "/right".to_string(),
-            valign: "/bottom".to_string(),
-            filename: "image.jpg".to_string(),
-        });
-
-        assert_eq!(url_props.width, 602);
-        assert_eq!(url_props.height, 303);
-        assert_eq!(url_props.filename, "image.jpg");
-        assert_eq!(url_props.alignment.halign, "right");
-        assert_eq!(url_props.alignment.valign, "bottom");
-        assert_eq!(url_props.alignment.smart, false);
-        assert_eq!(url_props.flip.horizontal, false);
-        assert_eq!(url_props.flip.vertical, false);
-    }
-}
\ No newline at end of file
+}